@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::config::JobConfig;
+use crate::input::InputSplit;
+use crate::reducer::ReduceSummary;
+use crate::transport;
+use crate::{Error, Result};
+
+mod coordinator;
+mod worker;
+pub use self::coordinator::run_coordinator;
+pub use self::worker::run_worker;
+
+/// Address the coordinator listens on when `--listen` is not given.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:7878";
+
+/// What the coordinator sends a worker.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ToWorker {
+    /// The answer to `Register`: everything a worker needs to run the job's
+    /// tasks. `worker` is the id the coordinator knows it by.
+    Welcome {
+        worker: usize,
+        config: Box<JobConfig>,
+        files: Vec<PathBuf>,
+    },
+    /// Map a split and drain it to the partitions before answering, so its
+    /// output is on disk once it is reported done.
+    Map { split: InputSplit },
+    /// Fetch the partition named `partition` from the shuffle server of every
+    /// worker in `sources`, sort it in the worker's scratch directory and
+    /// reduce it into the worker's `shuffle::reduced_file`, which the
+    /// coordinator fetches once it is answered. The coordinator numbers every
+    /// `attempt` apart.
+    Reduce {
+        partition: String,
+        sources: Vec<String>,
+        attempt: usize,
+    },
+    /// The job is over, the worker can exit.
+    Shutdown,
+}
+
+/// What a worker sends the coordinator.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ToCoordinator {
+    /// The first message of every worker, with the address its shuffle
+    /// server takes fetches of its map output on.
+    Register {
+        shuffle: String,
+    },
+    /// Sent every `heartbeat_interval`, busy or not, so the coordinator can
+    /// tell a slow worker from one that is gone.
+    Heartbeat,
+    /// A map task is done, with why each bad record left out of its split was
+    /// bad, or it failed.
+    Mapped(std::result::Result<Vec<String>, TaskError>),
+    Reduced(std::result::Result<ReduceSummary, TaskError>),
+}
+
+/// Why a task failed on a worker.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskError {
+    /// Whether running the task again could work, e.g. after a bad read of its input.
+    pub retryable: bool,
+    /// The shuffle server a reducer gave up fetching from, whose map output
+    /// has to be made again.
+    pub unreachable: Option<String>,
+    pub message: String,
+}
+
+impl From<Error> for TaskError {
+    fn from(e: Error) -> Self {
+        TaskError {
+            // the same errors a local map phase retries on a mapper it still
+            // has. a worker whose actor died leaves the job instead.
+            retryable: matches!(e, Error::InputError(..) | Error::FetchFailed { .. }),
+            unreachable: match &e {
+                Error::FetchFailed { addr, .. } => Some(addr.clone()),
+                _ => None,
+            },
+            message: e.to_string(),
+        }
+    }
+}
+
+/// Splits a coordinator/worker connection into its two directions, so one
+/// task can wait for messages while others send them. Every message goes in a
+/// frame of its own.
+pub fn split(stream: TcpStream) -> (Sender, Receiver) {
+    let (reader, writer) = stream.into_split();
+    (Sender(Arc::new(Mutex::new(writer))), Receiver(reader))
+}
+
+/// The sending half of a connection, shared by everything that talks on it.
+#[derive(Clone)]
+pub struct Sender(Arc<Mutex<OwnedWriteHalf>>);
+
+impl Sender {
+    pub async fn send<T: Serialize>(&self, message: &T) -> Result<()> {
+        transport::write_frame(&mut *self.0.lock().await, 0, message).await
+    }
+}
+
+/// The receiving half of a connection.
+pub struct Receiver(OwnedReadHalf);
+
+impl Receiver {
+    /// Waits for the next message, or `None` once the other end hung up.
+    pub async fn recv<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        Ok(transport::read_frame(&mut self.0)
+            .await?
+            .map(|(_, message)| message))
+    }
+}
+
+/// Removes `flag` and the value after it from `args`, returning the value.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>> {
+    let Some(at) = args.iter().position(|arg| arg == flag) else {
+        return Ok(None);
+    };
+    if at + 1 == args.len() {
+        return Err(Error::InvalidArguments(vec![format!(
+            "{} expects a value",
+            flag
+        )]));
+    }
+    let value = args.remove(at + 1);
+    args.remove(at);
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_flag() {
+        let mut args: Vec<String> = ["books", "--listen", "0.0.0.0:1", "-r", "2"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        assert_eq!(
+            take_flag(&mut args, "--listen").unwrap().as_deref(),
+            Some("0.0.0.0:1")
+        );
+        assert_eq!(args, vec!["books", "-r", "2"]);
+        assert_eq!(take_flag(&mut args, "--listen").unwrap(), None);
+        args.push(String::from("--connect"));
+        assert!(take_flag(&mut args, "--connect").is_err());
+    }
+
+    #[test]
+    fn test_task_error_retryable() {
+        let input = Error::InputError(PathBuf::from("a.txt"), std::io::ErrorKind::NotFound.into());
+        assert!(TaskError::from(input).retryable);
+        // a worker leaves the job rather than report its actor died
+        assert!(!TaskError::from(Error::ActorGone("mapper")).retryable);
+        // the coordinator maps what the unreachable worker held again
+        let fetch = Error::FetchFailed {
+            addr: String::from("10.0.0.2:4000"),
+            message: String::from("connection refused"),
+        };
+        let e = TaskError::from(fetch);
+        assert!(e.retryable);
+        assert_eq!(e.unreachable.as_deref(), Some("10.0.0.2:4000"));
+    }
+}
@@ -0,0 +1,1012 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex};
+
+use super::{split, take_flag, Receiver, Sender, TaskError, ToCoordinator, ToWorker, DEFAULT_ADDR};
+use crate::config::{self, JobConfig};
+use crate::input::InputSplit;
+use crate::reducer::ReduceSummary;
+use crate::{next_task, shuffle, Error, MapTask, Result, TaskQueue};
+
+/// A partition waiting to be reduced into `output`, from the map output on the
+/// shuffle servers in `sources`.
+#[derive(Debug, Clone)]
+struct ReduceTask {
+    partition: PathBuf,
+    output: PathBuf,
+    sources: Vec<String>,
+    /// Times it already failed.
+    attempts: usize,
+    /// Numbers every time it is handed out apart, so the files of no two
+    /// attempts at it share a name.
+    attempt: usize,
+}
+
+impl ReduceTask {
+    /// The name of the partition, the same in every worker's scratch directory.
+    fn name(&self) -> String {
+        let name = self.partition.file_name().unwrap_or_default();
+        name.to_string_lossy().into_owned()
+    }
+}
+
+#[derive(Debug)]
+enum Task {
+    Map(MapTask),
+    Reduce(ReduceTask),
+}
+
+/// What the connection of a worker reports for every task it handed out.
+#[derive(Debug)]
+enum Outcome {
+    Mapped(usize, MapTask, std::result::Result<Vec<String>, TaskError>),
+    Reduced(ReduceTask, std::result::Result<ReduceSummary, TaskError>),
+    /// The connection to a worker broke, while it ran a task or between two.
+    Lost(usize, Option<Task>),
+}
+
+/// What the connection of every worker takes tasks from and reports to.
+#[derive(Clone)]
+struct Tasks {
+    map: TaskQueue<MapTask>,
+    reduce: TaskQueue<ReduceTask>,
+    outcomes: mpsc::UnboundedSender<Outcome>,
+    status: Status,
+}
+
+/// Everything a worker sends but its heartbeats, ending with why it was lost.
+type Replies = mpsc::UnboundedReceiver<Result<ToCoordinator>>;
+
+type Status = Arc<std::sync::Mutex<JobStatus>>;
+
+/// Where a job is at and what every worker that joined it is up to.
+#[derive(Debug, Clone, Default)]
+pub struct JobStatus {
+    pub phase: &'static str,
+    /// Tasks of the current phase that are not done yet.
+    pub remaining: usize,
+    pub workers: BTreeMap<usize, WorkerStatus>,
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerStatus {
+    pub addr: SocketAddr,
+    pub state: WorkerState,
+    /// Where its shuffle server takes fetches, once it registered.
+    pub shuffle: Option<String>,
+    /// When the worker last sent anything, a heartbeat or a reply.
+    pub last_seen: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkerState {
+    Idle,
+    Busy,
+    /// Shut down once there was nothing left to do.
+    Done,
+    /// Went silent, hung up or answered nonsense; the reason why.
+    Lost(String),
+}
+
+impl JobStatus {
+    /// Returns whether the state changed; once lost, a worker stays lost.
+    fn set_state(&mut self, worker: usize, state: WorkerState) -> bool {
+        match self.workers.get_mut(&worker) {
+            Some(status)
+                if status.state != state && !matches!(status.state, WorkerState::Lost(_)) =>
+            {
+                status.state = state;
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for JobStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let alive = self
+            .workers
+            .values()
+            .filter(|worker| !matches!(worker.state, WorkerState::Lost(_)))
+            .count();
+        write!(
+            f,
+            "job status: {} phase, {} task(s) left, {} of {} worker(s) alive",
+            self.phase,
+            self.remaining,
+            alive,
+            self.workers.len()
+        )?;
+        for (id, worker) in &self.workers {
+            let state = match &worker.state {
+                WorkerState::Idle => String::from("idle"),
+                WorkerState::Busy => String::from("busy"),
+                WorkerState::Done => String::from("done"),
+                WorkerState::Lost(reason) => format!("lost ({})", reason),
+            };
+            write!(
+                f,
+                "\n  worker {} ({}): {}, last heard from {:?} ago",
+                id,
+                worker.addr,
+                state,
+                worker.last_seen.elapsed()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn print_status(status: &Status) {
+    println!("{}", status.lock().unwrap());
+}
+
+/// `tinymapreduce coordinator [--listen ADDR] [OPTIONS] <INPUT>...`: runs a job
+/// whose tasks are done by the workers that connect to ADDR.
+pub async fn run_coordinator(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let addr = take_flag(&mut args, "--listen")?.unwrap_or_else(|| String::from(DEFAULT_ADDR));
+    let config = JobConfig::from_args(&args).inspect_err(|_| eprintln!("{}\n", config::USAGE))?;
+    let files = config.input_files()?;
+    let listener = TcpListener::bind(&addr).await?;
+    println!("waiting for workers on {}", listener.local_addr()?);
+    coordinate(config, files, listener).await.map(|_| ())
+}
+
+/// Runs a whole job like `run_job`, except that every map and reduce task is
+/// handed to one of the workers that connect to `listener`. Workers can join
+/// at any time, and a worker that is lost has its task run again by another.
+/// A worker that sends nothing for `heartbeat_timeout` counts as lost too.
+/// Only the input has to be readable by every worker, at the same paths: map
+/// output and reduced partitions are fetched from the workers that hold them.
+/// Returns the status the job ended with.
+pub async fn coordinate(
+    config: JobConfig,
+    files: Vec<PathBuf>,
+    listener: TcpListener,
+) -> Result<JobStatus> {
+    let scratch_dir = crate::create_scratch_dir(&config.scratch_dir)?;
+    let status = Status::default();
+    let result = run_phases(&config, files, listener, &scratch_dir, &status).await;
+    crate::remove_scratch_dir(&config, &scratch_dir);
+    result?;
+    let status = status.lock().unwrap().clone();
+    Ok(status)
+}
+
+async fn run_phases(
+    config: &JobConfig,
+    files: Vec<PathBuf>,
+    listener: TcpListener,
+    scratch_dir: &Path,
+    status: &Status,
+) -> Result<()> {
+    let map_job = crate::word_count_job(config, &files);
+    let splits = crate::job_splits(config, &map_job, &files)?;
+
+    let (map_sender, map_receiver) = mpsc::unbounded_channel();
+    let (reduce_sender, reduce_receiver) = mpsc::unbounded_channel();
+    let (outcome_sender, mut outcomes) = mpsc::unbounded_channel();
+    let tasks = Tasks {
+        map: Arc::new(Mutex::new(map_receiver)),
+        reduce: Arc::new(Mutex::new(reduce_receiver)),
+        outcomes: outcome_sender,
+        status: status.clone(),
+    };
+    let acceptor = tokio::spawn(accept_workers(listener, config.clone(), files, tasks));
+    let mut map = MapTasks::new(map_sender);
+    let result = async {
+        map_phase(config, splits, &mut map, &mut outcomes, status).await?;
+        let partitions = map_job.partitioner.partitions();
+        let summaries = reduce_phase(
+            config,
+            scratch_dir,
+            partitions,
+            &mut map,
+            reduce_sender,
+            &mut outcomes,
+            status,
+        )
+        .await?;
+        crate::report_results(config, &summaries, &map.failures)
+    }
+    .await;
+    // workers still connected find both queues closed and are shut down
+    drop(map);
+    acceptor.abort();
+    status.lock().unwrap().phase = "finished";
+    result
+}
+
+async fn next_outcome(outcomes: &mut mpsc::UnboundedReceiver<Outcome>) -> Result<Outcome> {
+    // the acceptor holds a sender for as long as the job runs
+    outcomes.recv().await.ok_or(Error::ActorGone("coordinator"))
+}
+
+fn unexpected(outcome: Outcome) -> Error {
+    Error::Protocol(format!("unexpected outcome {:?}", outcome))
+}
+
+// ------------------ MAPPER ------------------
+
+/// The map tasks of a job and who mapped them. It outlives the map phase,
+/// since a worker lost while the partitions are reduced takes its map output
+/// with it, and its splits have to be mapped again then too.
+struct MapTasks {
+    queue: mpsc::UnboundedSender<MapTask>,
+    /// tasks each worker has mapped, in case it is lost
+    done: HashMap<usize, Vec<MapTask>>,
+    /// workers whose map output was thrown away
+    lost: Vec<usize>,
+    /// tasks handed out that are not mapped yet
+    outstanding: usize,
+    failures: Vec<(InputSplit, Error)>,
+}
+
+impl MapTasks {
+    fn new(queue: mpsc::UnboundedSender<MapTask>) -> Self {
+        MapTasks {
+            queue,
+            done: HashMap::new(),
+            lost: Vec::new(),
+            outstanding: 0,
+            failures: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, task: MapTask) {
+        self.outstanding += 1;
+        let _ = self.queue.send(task);
+    }
+
+    /// Retries a failed task, or skips it like the local map phase does.
+    fn retry(&mut self, config: &JobConfig, task: MapTask, error: Error) -> Result<()> {
+        if crate::retry_map_task(config, task, error, &self.queue, &mut self.failures)? {
+            self.outstanding += 1;
+        }
+        Ok(())
+    }
+
+    fn mapped(
+        &mut self,
+        config: &JobConfig,
+        worker: usize,
+        task: MapTask,
+        result: std::result::Result<Vec<String>, TaskError>,
+    ) -> Result<()> {
+        self.outstanding -= 1;
+        match result {
+            // nobody fetches the output of a worker given up on any more
+            Ok(_) if self.lost.contains(&worker) => self.send(task),
+            Ok(skipped) => {
+                crate::note_bad_records(&task.0, skipped, &mut self.failures);
+                self.done.entry(worker).or_default().push(task);
+            }
+            Err(e) if !e.retryable => return Err(Error::Remote(e.message)),
+            Err(e) => self.retry(config, task, Error::Remote(e.message))?,
+        }
+        Ok(())
+    }
+
+    /// Gives up on the map output of a lost worker, which is only on its disk,
+    /// and maps its splits again, along with `task`, the one it was mapping.
+    /// Returns whether the worker held any map output.
+    fn lose(&mut self, config: &JobConfig, worker: usize, task: Option<MapTask>) -> Result<bool> {
+        if !self.lost.contains(&worker) {
+            self.lost.push(worker);
+        }
+        let done = self.done.remove(&worker).unwrap_or_default();
+        let held_output = !done.is_empty();
+        if held_output || task.is_some() {
+            eprintln!("Worker {} was lost, mapping its splits again", worker);
+        }
+        for task in done {
+            self.send(task);
+        }
+        if let Some(task) = task {
+            self.outstanding -= 1;
+            let error = Error::Remote(format!("worker {} was lost", worker));
+            self.retry(config, task, error)?;
+        }
+        Ok(held_output)
+    }
+
+    /// The workers holding map output.
+    fn mappers(&self) -> Vec<usize> {
+        let mut mappers: Vec<usize> = self.done.keys().copied().collect();
+        mappers.sort();
+        mappers
+    }
+}
+
+/// Hands every split to the workers, retrying and skipping failed ones like the
+/// local map phase does, and waits until all of them are mapped. The output of
+/// a worker lost during the map phase is thrown away and its splits are mapped
+/// again.
+async fn map_phase(
+    config: &JobConfig,
+    splits: Vec<InputSplit>,
+    map: &mut MapTasks,
+    outcomes: &mut mpsc::UnboundedReceiver<Outcome>,
+    status: &Status,
+) -> Result<()> {
+    for split in splits {
+        map.send((split, 0));
+    }
+    while map.outstanding > 0 {
+        {
+            let mut status = status.lock().unwrap();
+            status.phase = "map";
+            status.remaining = map.outstanding;
+        }
+        match next_outcome(outcomes).await? {
+            Outcome::Mapped(worker, task, result) => map.mapped(config, worker, task, result)?,
+            Outcome::Lost(worker, None) => {
+                map.lose(config, worker, None)?;
+            }
+            Outcome::Lost(worker, Some(Task::Map(task))) => {
+                map.lose(config, worker, Some(task))?;
+            }
+            outcome => return Err(unexpected(outcome)),
+        }
+    }
+    Ok(())
+}
+
+// ------------------ REDUCER ------------------
+
+/// The addresses the shuffle servers of `workers` take fetches on.
+fn shuffle_addrs(status: &Status, workers: &[usize]) -> Result<Vec<String>> {
+    let status = status.lock().unwrap();
+    workers
+        .iter()
+        .map(|worker| {
+            status
+                .workers
+                .get(worker)
+                .and_then(|worker| worker.shuffle.clone())
+                .ok_or_else(|| Error::Protocol(format!("worker {} has no shuffle server", worker)))
+        })
+        .collect()
+}
+
+/// The worker whose shuffle server takes fetches on `addr`.
+fn worker_at(status: &Status, addr: &str) -> Option<usize> {
+    let status = status.lock().unwrap();
+    status
+        .workers
+        .iter()
+        .find(|(_, worker)| worker.shuffle.as_deref() == Some(addr))
+        .map(|(id, _)| *id)
+}
+
+/// Hands every partition to the workers, which fetch it from the shuffle
+/// servers of the workers holding map output. A partition that cannot be
+/// reduced, after its retries if the error is worth retrying, fails the job.
+/// A worker holding map output that is lost, or whose shuffle server a
+/// reducer gives up on, has its splits mapped again like in the map phase.
+/// The partitions wait for that, and are then reduced from the new sources
+/// without it counting against them.
+async fn reduce_phase(
+    config: &JobConfig,
+    scratch_dir: &Path,
+    partitions: usize,
+    map: &mut MapTasks,
+    queue: mpsc::UnboundedSender<ReduceTask>,
+    outcomes: &mut mpsc::UnboundedReceiver<Outcome>,
+    status: &Status,
+) -> Result<Vec<ReduceSummary>> {
+    // the partitions are spread over the workers' disks, so there is no
+    // listing them; every one a mapper could have drained into is reduced
+    let partitions: Vec<PathBuf> = if map.done.is_empty() {
+        Vec::new()
+    } else {
+        (0..partitions)
+            .map(|partition| scratch_dir.join(partition.to_string()))
+            .collect()
+    };
+    println!("reducing {} partition(s)", partitions.len());
+    std::fs::create_dir_all(&config.output_dir)?;
+    let (output_format, output_dir) = crate::reduce_output(config, scratch_dir);
+    let mut outstanding = partitions.len();
+    // tasks waiting to be handed out once the map output they need is there
+    let mut waiting: Vec<ReduceTask> = partitions
+        .into_iter()
+        .map(|partition| ReduceTask {
+            output: crate::part_file(&output_dir, &partition, output_format.extension()),
+            partition,
+            sources: Vec::new(),
+            attempts: 0,
+            attempt: 0,
+        })
+        .collect();
+    // none while the map output of a lost worker is made again
+    let mut sources = None;
+    let mut handed_out = 0;
+
+    let mut summaries = Vec::new();
+    while outstanding > 0 {
+        if sources.is_none() && map.outstanding == 0 {
+            sources = Some(shuffle_addrs(status, &map.mappers())?);
+        }
+        if let Some(sources) = &sources {
+            for mut task in waiting.drain(..) {
+                task.sources = sources.clone();
+                handed_out += 1;
+                task.attempt = handed_out;
+                let _ = queue.send(task);
+            }
+        }
+        {
+            let mut status = status.lock().unwrap();
+            status.phase = "reduce";
+            status.remaining = outstanding + map.outstanding;
+        }
+        let (mut task, e) = match next_outcome(outcomes).await? {
+            Outcome::Mapped(worker, task, result) => {
+                map.mapped(config, worker, task, result)?;
+                continue;
+            }
+            Outcome::Reduced(task, Ok(summary)) => {
+                if sources.as_ref() == Some(&task.sources) {
+                    outstanding -= 1;
+                    summaries.push(summary);
+                } else {
+                    // reduced from map output that was thrown away since
+                    waiting.push(task);
+                }
+                continue;
+            }
+            Outcome::Reduced(task, Err(e)) => (task, e),
+            Outcome::Lost(worker, task) => {
+                let (map_task, reduce_task) = match task {
+                    Some(Task::Map(task)) => (Some(task), None),
+                    Some(Task::Reduce(task)) => (None, Some(task)),
+                    None => (None, None),
+                };
+                if map.lose(config, worker, map_task)? {
+                    sources = None;
+                }
+                let Some(task) = reduce_task else {
+                    continue;
+                };
+                let e = TaskError {
+                    retryable: true,
+                    unreachable: None,
+                    message: format!("worker {} was lost", worker),
+                };
+                (task, e)
+            }
+        };
+        // a worker whose map output can't be fetched is as good as lost
+        let unreachable = e
+            .unreachable
+            .as_deref()
+            .and_then(|addr| worker_at(status, addr));
+        if let Some(worker) = unreachable.filter(|worker| map.done.contains_key(worker)) {
+            mark_lost(status, worker, &Error::Remote(e.message.clone()));
+            map.lose(config, worker, None)?;
+            sources = None;
+        }
+        if sources.as_ref() != Some(&task.sources) {
+            waiting.push(task);
+            continue;
+        }
+        task.attempts += 1;
+        if !e.retryable || task.attempts > config.max_retries {
+            return Err(Error::Remote(format!(
+                "reducing {} failed after {} attempt(s): {}",
+                task.partition.display(),
+                task.attempts,
+                e.message
+            )));
+        }
+        eprintln!(
+            "Failed to reduce {} (attempt {}): {}, retrying",
+            task.partition.display(),
+            task.attempts,
+            e.message
+        );
+        waiting.push(task);
+    }
+    Ok(summaries)
+}
+
+// ------------------ WORKERS ------------------
+
+async fn accept_workers(
+    listener: TcpListener,
+    config: JobConfig,
+    files: Vec<PathBuf>,
+    tasks: Tasks,
+) {
+    for worker in 0.. {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("could not accept a worker: {}", e);
+                continue;
+            }
+        };
+        println!("worker {} connected from {}", worker, addr);
+        tasks.status.lock().unwrap().workers.insert(
+            worker,
+            WorkerStatus {
+                addr,
+                state: WorkerState::Idle,
+                shuffle: None,
+                last_seen: Instant::now(),
+            },
+        );
+        print_status(&tasks.status);
+        let welcome = ToWorker::Welcome {
+            worker,
+            config: Box::new(config.clone()),
+            files: files.clone(),
+        };
+        let timeout = config.heartbeat_timeout;
+        let tasks = tasks.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_worker(worker, stream, welcome, timeout, &tasks).await {
+                eprintln!("worker {}: {}", worker, e);
+                mark_lost(&tasks.status, worker, &e);
+            }
+        });
+    }
+}
+
+fn is_lost(status: &Status, worker: usize) -> bool {
+    let status = status.lock().unwrap();
+    status
+        .workers
+        .get(&worker)
+        .is_some_and(|worker| matches!(worker.state, WorkerState::Lost(_)))
+}
+
+fn mark_lost(status: &Status, worker: usize, reason: &Error) {
+    let state = WorkerState::Lost(reason.to_string());
+    if status.lock().unwrap().set_state(worker, state) {
+        print_status(status);
+    }
+}
+
+/// Sends a request to a worker and waits for its answer.
+async fn call(sender: &Sender, replies: &mut Replies, request: &ToWorker) -> Result<ToCoordinator> {
+    sender.send(request).await?;
+    replies.recv().await.unwrap_or_else(|| Err(hung_up()))
+}
+
+fn hung_up() -> Error {
+    Error::Protocol(String::from("worker hung up"))
+}
+
+/// Waits for the next task, a map task if there is one, unless the worker is
+/// lost before one comes up. There are none left once the reduce queue is
+/// closed.
+async fn wait_for_task(
+    worker: usize,
+    replies: &mut Replies,
+    tasks: &Tasks,
+) -> Result<Option<Task>> {
+    let task = tokio::select! {
+        biased;
+        Some(task) = next_task(&tasks.map) => Some(Task::Map(task)),
+        task = next_task(&tasks.reduce) => task.map(Task::Reduce),
+        reply = replies.recv() => {
+            let e = lost(reply.unwrap_or_else(|| Err(hung_up())));
+            mark_lost(&tasks.status, worker, &e);
+            let _ = tasks.outcomes.send(Outcome::Lost(worker, None));
+            return Err(e);
+        }
+    };
+    match task {
+        // given up on while it waited, since its map output could not be fetched
+        Some(task) if is_lost(&tasks.status, worker) => {
+            let _ = tasks.outcomes.send(Outcome::Lost(worker, Some(task)));
+            Err(Error::Protocol(String::from(
+                "given up on by the coordinator",
+            )))
+        }
+        task => Ok(task),
+    }
+}
+
+/// Feeds one worker tasks, map tasks before reduce tasks, until the job is
+/// over, and fetches the result of every partition it reduces. Any answer but
+/// the one expected counts as losing the worker, and so does going quiet for
+/// `timeout`.
+async fn serve_worker(
+    worker: usize,
+    stream: TcpStream,
+    welcome: ToWorker,
+    timeout: Duration,
+    tasks: &Tasks,
+) -> Result<()> {
+    let (sender, mut receiver) = split(stream);
+    match tokio::time::timeout(timeout, receiver.recv()).await {
+        Ok(Ok(Some(ToCoordinator::Register { shuffle }))) => {
+            if let Some(worker) = tasks.status.lock().unwrap().workers.get_mut(&worker) {
+                worker.shuffle = Some(shuffle);
+            }
+        }
+        Ok(other) => {
+            return Err(Error::Protocol(format!(
+                "expected a registration, got {:?}",
+                other
+            )))
+        }
+        Err(_) => return Err(silent(timeout)),
+    }
+    sender.send(&welcome).await?;
+
+    let (reply_sender, mut replies) = mpsc::unbounded_channel();
+    let listener = tokio::spawn(listen(
+        worker,
+        receiver,
+        timeout,
+        reply_sender,
+        tasks.status.clone(),
+    ));
+    let result = run_tasks(worker, &sender, &mut replies, tasks).await;
+    // dropping the receiving half as well closes the connection
+    listener.abort();
+    result
+}
+
+async fn run_tasks(
+    worker: usize,
+    sender: &Sender,
+    replies: &mut Replies,
+    tasks: &Tasks,
+) -> Result<()> {
+    let set_state = |state| {
+        tasks.status.lock().unwrap().set_state(worker, state);
+    };
+    while let Some(task) = wait_for_task(worker, replies, tasks).await? {
+        set_state(WorkerState::Busy);
+        let request = match &task {
+            Task::Map(task) => ToWorker::Map {
+                split: task.0.clone(),
+            },
+            Task::Reduce(task) => ToWorker::Reduce {
+                partition: task.name(),
+                sources: task.sources.clone(),
+                attempt: task.attempt,
+            },
+        };
+        let outcome = match (call(sender, replies, &request).await, task) {
+            (Ok(ToCoordinator::Mapped(result)), Task::Map(task)) => {
+                Outcome::Mapped(worker, task, result)
+            }
+            (Ok(ToCoordinator::Reduced(result)), Task::Reduce(task)) => {
+                let result = match result {
+                    Ok(summary) => fetch_result(worker, &task, summary, &tasks.status).await,
+                    Err(e) => Err(e),
+                };
+                Outcome::Reduced(task, result)
+            }
+            (reply, task) => {
+                let e = lost(reply);
+                mark_lost(&tasks.status, worker, &e);
+                let _ = tasks.outcomes.send(Outcome::Lost(worker, Some(task)));
+                return Err(e);
+            }
+        };
+        set_state(WorkerState::Idle);
+        let _ = tasks.outcomes.send(outcome);
+    }
+    sender.send(&ToWorker::Shutdown).await?;
+    set_state(WorkerState::Done);
+    Ok(())
+}
+
+/// Fetches the result of a reduce task from the shuffle server of the worker
+/// that reduced it into the task's `output`. A failed fetch fails the task.
+async fn fetch_result(
+    worker: usize,
+    task: &ReduceTask,
+    mut summary: ReduceSummary,
+    status: &Status,
+) -> std::result::Result<ReduceSummary, TaskError> {
+    let fetched = match shuffle_addrs(status, &[worker]) {
+        Ok(addrs) => {
+            shuffle::fetch_reduced(&addrs[0], &task.name(), task.attempt, &task.output).await
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = fetched {
+        return Err(TaskError {
+            retryable: true,
+            unreachable: None,
+            message: format!("fetching the result from worker {}: {}", worker, e),
+        });
+    }
+    summary.output = task.output.clone();
+    Ok(summary)
+}
+
+/// Reads everything a worker sends, noting when it was last heard from and
+/// passing on all but its heartbeats. Once the worker hangs up, or says
+/// nothing at all for `timeout`, the last thing passed on is why it was lost.
+async fn listen(
+    worker: usize,
+    mut receiver: Receiver,
+    timeout: Duration,
+    replies: mpsc::UnboundedSender<Result<ToCoordinator>>,
+    status: Status,
+) {
+    let reason = loop {
+        let message = match tokio::time::timeout(timeout, receiver.recv()).await {
+            Ok(Ok(Some(message))) => message,
+            Ok(Ok(None)) => break hung_up(),
+            Ok(Err(e)) => break e,
+            Err(_) => break silent(timeout),
+        };
+        if let Some(worker) = status.lock().unwrap().workers.get_mut(&worker) {
+            worker.last_seen = Instant::now();
+        }
+        if matches!(message, ToCoordinator::Heartbeat) {
+            continue;
+        }
+        if replies.send(Ok(message)).is_err() {
+            return;
+        }
+    };
+    let _ = replies.send(Err(reason));
+}
+
+fn silent(timeout: Duration) -> Error {
+    Error::Protocol(format!("no heartbeat for {:?}", timeout))
+}
+
+fn lost(reply: Result<ToCoordinator>) -> Error {
+    match reply {
+        Ok(reply) => Error::Protocol(format!("unexpected reply {:?}", reply)),
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::worker::work;
+
+    fn test_config(name: &str, extra_args: &[&str]) -> (JobConfig, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("input")).unwrap();
+        for (name, contents) in [
+            ("a.txt", "hello world\nhello\n"),
+            ("b.txt", "world hello\nboom\n"),
+            ("c.txt", "hello\n"),
+        ] {
+            std::fs::write(dir.join("input").join(name), contents).unwrap();
+        }
+        let args: Vec<String> = [
+            dir.join("input").to_str().unwrap(),
+            "-o",
+            dir.join("output").to_str().unwrap(),
+            "-s",
+            dir.join("scratch").to_str().unwrap(),
+            "-r",
+            "2",
+            "--split-size",
+            "8",
+        ]
+        .iter()
+        .chain(extra_args)
+        .map(|arg| arg.to_string())
+        .collect();
+        (JobConfig::from_args(&args).unwrap(), dir)
+    }
+
+    /// Runs a worker whose shuffle server listens on any free port.
+    async fn real_worker(addr: SocketAddr) -> Result<()> {
+        worker_with_scratch(addr, None).await
+    }
+
+    /// Runs a worker that keeps its scratch directory under `scratch_root`.
+    async fn worker_with_scratch(addr: SocketAddr, scratch_root: Option<PathBuf>) -> Result<()> {
+        let shuffle = TcpListener::bind("127.0.0.1:0").await?;
+        work(TcpStream::connect(addr).await?, shuffle, scratch_root).await
+    }
+
+    /// Registers as worker 0 and takes the first map task, without ever answering.
+    async fn fake_worker(addr: SocketAddr) -> (Sender, Receiver) {
+        let (sender, mut receiver) = split(TcpStream::connect(addr).await.unwrap());
+        let register = ToCoordinator::Register {
+            // never fetched from, the worker is lost before it maps anything
+            shuffle: String::from("127.0.0.1:1"),
+        };
+        sender.send(&register).await.unwrap();
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            Some(ToWorker::Welcome { worker: 0, .. })
+        ));
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            Some(ToWorker::Map { .. })
+        ));
+        (sender, receiver)
+    }
+
+    /// Registers as worker 0 and answers every map task as done, without
+    /// draining a thing, until it is handed a reduce task.
+    async fn fake_mapper(addr: SocketAddr) -> (Sender, Receiver) {
+        let (sender, mut receiver) = split(TcpStream::connect(addr).await.unwrap());
+        let register = ToCoordinator::Register {
+            // nothing listens here, so its map output can't be fetched
+            shuffle: String::from("127.0.0.1:1"),
+        };
+        sender.send(&register).await.unwrap();
+        assert!(matches!(
+            receiver.recv().await.unwrap(),
+            Some(ToWorker::Welcome { worker: 0, .. })
+        ));
+        loop {
+            match receiver.recv().await.unwrap() {
+                Some(ToWorker::Map { .. }) => {
+                    sender
+                        .send(&ToCoordinator::Mapped(Ok(Vec::new())))
+                        .await
+                        .unwrap();
+                }
+                Some(ToWorker::Reduce { .. }) => return (sender, receiver),
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_with_workers() {
+        let (config, dir) = test_config("cluster-test", &[]);
+        let files = config.input_files().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let coordinator = tokio::spawn(coordinate(config.clone(), files, listener));
+        let workers: Vec<_> = (0..3)
+            .map(|_| tokio::spawn(real_worker(addr)))
+            .collect();
+        coordinator.await.unwrap().unwrap();
+        for worker in workers {
+            worker.await.unwrap().unwrap();
+        }
+        let result = std::fs::read_to_string(config.output_dir.join("result.tsv")).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t4\nworld\t2\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_workers_remove_scratch() {
+        let (config, dir) = test_config("cluster-scratch-test", &[]);
+        let files = config.input_files().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let coordinator = tokio::spawn(coordinate(config.clone(), files, listener));
+
+        // one worker on a disk of its own, the other sharing the coordinator's
+        let own_scratch = dir.join("worker-scratch");
+        let workers = [
+            tokio::spawn(worker_with_scratch(addr, Some(own_scratch.clone()))),
+            tokio::spawn(real_worker(addr)),
+        ];
+        coordinator.await.unwrap().unwrap();
+        for worker in workers {
+            worker.await.unwrap().unwrap();
+        }
+        let result = std::fs::read_to_string(config.output_dir.join("result.tsv")).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t4\nworld\t2\n");
+        for scratch_root in [&own_scratch, &config.scratch_dir] {
+            let leftovers: Vec<_> = std::fs::read_dir(scratch_root).unwrap().collect();
+            assert!(
+                leftovers.is_empty(),
+                "left in {}: {:?}",
+                scratch_root.display(),
+                leftovers
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_requeues_lost_worker() {
+        let (config, dir) = test_config("cluster-lost-test", &[]);
+        let files = config.input_files().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let coordinator = tokio::spawn(coordinate(config.clone(), files, listener));
+
+        // a worker that takes a task and dies without answering
+        let connection = fake_worker(addr).await;
+        drop(connection);
+
+        real_worker(addr).await.unwrap();
+        coordinator.await.unwrap().unwrap();
+        let result = std::fs::read_to_string(config.output_dir.join("result.tsv")).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t4\nworld\t2\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_remaps_mapper_lost_while_reducing() {
+        let (config, dir) = test_config("cluster-lost-mapper-test", &[]);
+        let files = config.input_files().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let coordinator = tokio::spawn(coordinate(config.clone(), files, listener));
+
+        // a worker that mapped every split, and dies once the reduce phase began
+        let connection = fake_mapper(addr).await;
+        let worker = tokio::spawn(real_worker(addr));
+        drop(connection);
+
+        let status = coordinator.await.unwrap().unwrap();
+        worker.await.unwrap().unwrap();
+        let result = std::fs::read_to_string(config.output_dir.join("result.tsv")).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t4\nworld\t2\n");
+        assert!(matches!(status.workers[&0].state, WorkerState::Lost(_)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_remaps_unreachable_mapper() {
+        let (config, dir) = test_config("cluster-unreachable-test", &[]);
+        let files = config.input_files().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let coordinator = tokio::spawn(coordinate(config.clone(), files, listener));
+
+        // a worker that mapped every split, but whose output no reducer can fetch
+        let (sender, _receiver) = fake_mapper(addr).await;
+        let worker = tokio::spawn(real_worker(addr));
+        let unreachable = Error::FetchFailed {
+            addr: String::from("127.0.0.1:1"),
+            message: String::from("connection refused"),
+        };
+        let reduced = ToCoordinator::Reduced(Err(TaskError::from(unreachable)));
+        sender.send(&reduced).await.unwrap();
+
+        let status = coordinator.await.unwrap().unwrap();
+        worker.await.unwrap().unwrap();
+        let result = std::fs::read_to_string(config.output_dir.join("result.tsv")).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t4\nworld\t2\n");
+        assert!(matches!(
+            &status.workers[&0].state,
+            WorkerState::Lost(reason) if reason.contains("127.0.0.1:1")
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_coordinator_requeues_silent_worker() {
+        let extra_args = ["--heartbeat-interval", "50", "--heartbeat-timeout", "300"];
+        let (config, dir) = test_config("cluster-silent-test", &extra_args);
+        let files = config.input_files().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let coordinator = tokio::spawn(coordinate(config.clone(), files, listener));
+
+        // a worker that hangs on to its task and its connection, but never
+        // sends a heartbeat
+        let connection = fake_worker(addr).await;
+
+        real_worker(addr).await.unwrap();
+        let status = coordinator.await.unwrap().unwrap();
+        let result = std::fs::read_to_string(config.output_dir.join("result.tsv")).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t4\nworld\t2\n");
+        assert!(matches!(
+            &status.workers[&0].state,
+            WorkerState::Lost(reason) if reason.contains("no heartbeat")
+        ));
+        assert!(!matches!(status.workers[&1].state, WorkerState::Lost(_)));
+        drop(connection);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
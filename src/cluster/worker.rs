@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::{TcpListener, TcpStream};
+
+use super::{split, take_flag, Sender, TaskError, ToCoordinator, ToWorker, DEFAULT_ADDR};
+use crate::{job, mapper, reducer, shuffle, writer, Error, Result};
+
+/// `tinymapreduce worker [--connect ADDR] [--shuffle-listen ADDR] [--scratch DIR]`:
+/// runs tasks for the coordinator at `--connect` until it says the job is over,
+/// serving its map output to other workers on `--shuffle-listen`. Its scratch
+/// directory goes under `--scratch`, by default the job's.
+pub async fn run_worker(args: &[String]) -> Result<()> {
+    let mut args = args.to_vec();
+    let addr = take_flag(&mut args, "--connect")?.unwrap_or_else(|| String::from(DEFAULT_ADDR));
+    let shuffle_addr = take_flag(&mut args, "--shuffle-listen")?;
+    let scratch_root = take_flag(&mut args, "--scratch")?.map(PathBuf::from);
+    if !args.is_empty() {
+        return Err(Error::InvalidArguments(args));
+    }
+    let stream = TcpStream::connect(&addr).await?;
+    println!("connected to {}", addr);
+    // by default, on any port of the interface the coordinator is reached on
+    let shuffle = match shuffle_addr {
+        Some(shuffle_addr) => TcpListener::bind(shuffle_addr).await?,
+        None => TcpListener::bind((stream.local_addr()?.ip(), 0)).await?,
+    };
+    work(stream, shuffle, scratch_root).await
+}
+
+/// Registers with the coordinator on the other end of `stream` and runs every
+/// task it hands out, one at a time, with a mapper and a reducer of its own.
+/// Other workers fetch its map output from `shuffle`, and the coordinator the
+/// partitions it reduced. Everything it writes goes in a scratch directory of
+/// its own under `scratch_root`, or the job's scratch root if there is none,
+/// which is removed once the job is over unless the job keeps its scratch.
+/// Returns once the coordinator shuts the worker down or goes away.
+pub async fn work(
+    stream: TcpStream,
+    shuffle: TcpListener,
+    scratch_root: Option<PathBuf>,
+) -> Result<()> {
+    let mut shuffle_addr = shuffle.local_addr()?;
+    if shuffle_addr.ip().is_unspecified() {
+        // listening everywhere, so any address the coordinator can reach works
+        shuffle_addr.set_ip(stream.local_addr()?.ip());
+    }
+    let (sender, mut receiver) = split(stream);
+    let register = ToCoordinator::Register {
+        shuffle: shuffle_addr.to_string(),
+    };
+    sender.send(&register).await?;
+    let (worker, config, files) = match receiver.recv().await? {
+        Some(ToWorker::Welcome {
+            worker,
+            config,
+            files,
+        }) => (worker, config, files),
+        Some(other) => {
+            return Err(Error::Protocol(format!(
+                "expected a welcome, got {:?}",
+                other
+            )))
+        }
+        None => return Ok(()),
+    };
+    println!("registered as worker {}", worker);
+    // apart from the coordinator's, even on the same disk, so it can be
+    // removed while the coordinator still merges from its own
+    let scratch_root = scratch_root.unwrap_or_else(|| config.scratch_dir.clone());
+    let scratch_dir = crate::create_scratch_dir(&scratch_root)?;
+    let heartbeat = tokio::spawn(heartbeat(sender.clone(), config.heartbeat_interval));
+    let prefix = format!("w{}-", worker);
+    let shuffle_server = tokio::spawn(shuffle::serve(shuffle, scratch_dir.clone(), prefix.clone()));
+
+    let mut map_job = crate::word_count_job(&config, &files);
+    map_job.scratch_dir = scratch_dir.clone();
+    // partitions fetched from several workers hold the drains of all of them
+    map_job.drain_prefix = prefix;
+    let mut writer_handle =
+        writer::WriterHandle::with_format(config.record_format, config.compression).await;
+    let mapper = mapper::HandleMapper::new(writer_handle.clone(), map_job.clone());
+    let (output_format, _) = crate::reduce_output(&config, &scratch_dir);
+    let reducer = reducer::HandleReducer::with_output_format(
+        Arc::new(job::WordCount),
+        config.record_format,
+        config.compression,
+        output_format,
+    );
+
+    let result = async {
+        loop {
+            match receiver.recv().await? {
+                Some(ToWorker::Map { split }) => {
+                    // drained straight away, a task is only done once its output is committed
+                    let mut result = mapper
+                        .process_file_with_buffer(split)
+                        .await
+                        .map(|(_, skipped)| skipped);
+                    if let Ok(skipped) = result {
+                        result = mapper.cleanup_signal().await.map(|_| skipped);
+                    }
+                    // a drain commits its partitions one file at a time, so a mapper
+                    // or writer that died halfway through one may have committed
+                    // some of them, which the shuffle server would go on serving.
+                    // giving up on the whole worker makes the coordinator throw its
+                    // map output away and map it again.
+                    if let Err(Error::ActorGone(actor)) = result {
+                        return Err(Error::ActorGone(actor));
+                    }
+                    let result = result.map_err(TaskError::from);
+                    sender.send(&ToCoordinator::Mapped(result)).await?;
+                }
+                Some(ToWorker::Reduce {
+                    partition,
+                    sources,
+                    attempt,
+                }) => {
+                    // kept on this worker's disk until the coordinator fetches it
+                    let output = shuffle::reduced_file(&scratch_dir, &partition, attempt);
+                    let partition = scratch_dir.join(partition);
+                    let result =
+                        crate::reduce_partition(&reducer, &partition, &sources, attempt, output)
+                            .await;
+                    if let Err(Error::ActorGone(actor)) = result {
+                        return Err(Error::ActorGone(actor));
+                    }
+                    let result = result.map_err(TaskError::from);
+                    sender.send(&ToCoordinator::Reduced(result)).await?;
+                }
+                Some(ToWorker::Shutdown) | None => return Ok(()),
+                Some(other) => {
+                    return Err(Error::Protocol(format!("unexpected message {:?}", other)))
+                }
+            }
+        }
+    }
+    .await;
+    heartbeat.abort();
+    // the coordinator only shuts workers down once every partition is reduced,
+    // so nobody needs this worker's map output any more
+    shuffle_server.abort();
+    let shutdown = writer_handle.shutdown().await;
+    crate::remove_scratch_dir(&config, &scratch_dir);
+    shutdown?;
+    result
+}
+
+/// Tells the coordinator the worker is still there every `interval`, until the
+/// connection breaks.
+async fn heartbeat(sender: Sender, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        if sender.send(&ToCoordinator::Heartbeat).await.is_err() {
+            return;
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::input::{self, InputFilter, InvalidUtf8};
+use crate::record::{Compression, RecordFormat};
+use crate::reducer::{output, OutputFormat};
+use crate::{Error, Result};
+
+pub const USAGE: &str = "\
+usage: tinymapreduce [OPTIONS] <INPUT>...
+       tinymapreduce coordinator [--listen <ADDR>] [OPTIONS] <INPUT>...
+       tinymapreduce worker [--connect <ADDR>] [--shuffle-listen <ADDR>] [--scratch <DIR>]
+
+INPUT is a file, a directory (walked recursively) or a glob pattern. Several can be given.
+
+A coordinator runs the job's map and reduce tasks on the workers that connect to it over
+TCP instead of in its own process [ADDR default: 127.0.0.1:7878]. Every worker serves its
+map output to the reducers on --shuffle-listen [default: a free port on the address it reaches
+the coordinator from], and the coordinator fetches the partitions it reduced from there too.
+A worker keeps its map output and reduced partitions under --scratch [default: the job's].
+Only the input has to be readable by every worker, at the same paths.
+
+options:
+  -c, --config <FILE>       read job settings from a TOML file; flags override it
+  -o, --output <DIR>        where the reduced results are written [default: ./output]
+      --output-format <FMT> tsv, csv or jsonl results [default: tsv]
+      --no-merge            leave the results of every partition in a file of its own instead of
+                            merging them into a single result file sorted by key
+      --include <GLOB>      only map files below an input directory whose path matches; repeatable
+      --exclude <GLOB>      don't map files below an input directory whose path matches; repeatable
+      --follow-symlinks     follow symlinks found in input directories instead of skipping them
+      --hidden              also map files and directories whose name starts with a dot
+      --input-format <FMT>  lines (every line is a record), whole (every file is one record),
+                            csv (the fields of every row after the header) or jsonl (the
+                            strings of every line's JSON document) [default: lines]
+      --invalid-utf8 <POLICY>
+                            fail (the task), replace (bad bytes with U+FFFD) or skip (the record)
+                            when input is not valid UTF-8 [default: fail]
+  -s, --scratch <DIR>       where each job creates its scratch directory for intermediate
+                            partitions [default: <system temp dir>/tinymapreduce]
+      --keep-scratch        keep the job's scratch directory instead of removing it at the end
+  -m, --mappers <N>         number of mapper actors [default: number of cpus]
+      --processes           run every mapper and reducer in a child process of its own, so a
+                            crashing one only takes its own tasks down
+  -r, --reducers <N>        number of reducers and partitions [default: number of cpus]
+      --split-size <BYTES>  input files are mapped in splits of about this size, cut at line
+                            ends [default: 1048576]
+      --flush-every <N>     splits a mapper buffers before draining to the partitions [default: 10]
+      --partitioner <KIND>  hash or range [default: hash]
+      --max-retries <N>     times a failed map task is retried [default: 2]
+      --on-failure <POLICY> fail (stop the job) or skip (leave the input out and report it)
+                            once a task runs out of retries; skip also leaves out just the
+                            records the map function panics on or that can't be parsed
+                            [default: fail]
+      --record-format <FMT> binary or text (for debugging) intermediate records [default: binary]
+      --compression <KIND>  none or lz4 compression of binary intermediate records [default: none]
+      --heartbeat-interval <MS>
+                            how often a worker tells its coordinator it is alive [default: 1000]
+      --heartbeat-timeout <MS>
+                            how long a coordinator waits to hear from a worker before it gives
+                            the worker up and runs its tasks elsewhere [default: 10000]
+  -h, --help                print this message";
+
+/// How intermediate keys are spread over the reducers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionerKind {
+    Hash,
+    Range,
+}
+
+impl FromStr for PartitionerKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "hash" => Ok(PartitionerKind::Hash),
+            "range" => Ok(PartitionerKind::Range),
+            _ => Err(format!(
+                "unknown partitioner {:?}, expected hash or range",
+                s
+            )),
+        }
+    }
+}
+
+/// How input files are read into records for the word count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InputFormatKind {
+    Lines,
+    Whole,
+    /// The fields of every CSV row, after a header row.
+    Csv,
+    /// The strings of every JSON document, one per line.
+    Jsonl,
+}
+
+impl FromStr for InputFormatKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "lines" => Ok(InputFormatKind::Lines),
+            "whole" => Ok(InputFormatKind::Whole),
+            "csv" => Ok(InputFormatKind::Csv),
+            "jsonl" => Ok(InputFormatKind::Jsonl),
+            _ => Err(format!(
+                "unknown input format {:?}, expected lines, whole, csv or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+/// How the reduced results are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormatKind {
+    Tsv,
+    Csv,
+    Jsonl,
+}
+
+impl FromStr for OutputFormatKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "tsv" => Ok(OutputFormatKind::Tsv),
+            "csv" => Ok(OutputFormatKind::Csv),
+            "jsonl" => Ok(OutputFormatKind::Jsonl),
+            _ => Err(format!(
+                "unknown output format {:?}, expected tsv, csv or jsonl",
+                s
+            )),
+        }
+    }
+}
+
+/// What happens to a bad record, and to a map task that still fails after all
+/// of its retries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Stop the whole job with the task's error. A record the map function
+    /// panics on fails its task, which is retried like any other.
+    Fail,
+    /// Leave the input out of the results and report it at the end of the job:
+    /// just the record for a bad record, the whole split for a task.
+    Skip,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(FailurePolicy::Fail),
+            "skip" => Ok(FailurePolicy::Skip),
+            _ => Err(format!(
+                "unknown failure policy {:?}, expected fail or skip",
+                s
+            )),
+        }
+    }
+}
+
+/// Everything a job run needs to know, after the config file and the command
+/// line have been merged and validated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobConfig {
+    pub inputs: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub follow_symlinks: bool,
+    pub hidden: bool,
+    pub input_format: InputFormatKind,
+    pub invalid_utf8: InvalidUtf8,
+    pub output_dir: PathBuf,
+    pub output_format: OutputFormatKind,
+    /// Whether the partitions' results are merged into a single file.
+    pub merge: bool,
+    /// Root under which every job run creates its own scratch directory.
+    pub scratch_dir: PathBuf,
+    pub keep_scratch: bool,
+    /// Whether mappers and reducers run in child processes instead of tasks.
+    pub processes: bool,
+    pub mappers: usize,
+    pub reducers: usize,
+    pub split_bytes: u64,
+    pub flush_every: usize,
+    pub partitioner: PartitionerKind,
+    pub max_retries: usize,
+    pub on_failure: FailurePolicy,
+    pub record_format: RecordFormat,
+    pub compression: Compression,
+    /// How often a worker sends its coordinator a heartbeat.
+    pub heartbeat_interval: Duration,
+    /// How long a worker may stay silent before its coordinator gives it up.
+    pub heartbeat_timeout: Duration,
+}
+
+/// The optional TOML job file. Every setting can also be given on the command line.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct JobFile {
+    inputs: Vec<String>,
+    include: Vec<String>,
+    exclude: Vec<String>,
+    follow_symlinks: Option<bool>,
+    hidden: Option<bool>,
+    input_format: Option<InputFormatKind>,
+    invalid_utf8: Option<InvalidUtf8>,
+    output: Option<PathBuf>,
+    output_format: Option<OutputFormatKind>,
+    merge: Option<bool>,
+    scratch: Option<PathBuf>,
+    keep_scratch: Option<bool>,
+    processes: Option<bool>,
+    mappers: Option<usize>,
+    reducers: Option<usize>,
+    split_size: Option<u64>,
+    flush_every: Option<usize>,
+    partitioner: Option<PartitionerKind>,
+    max_retries: Option<usize>,
+    on_failure: Option<FailurePolicy>,
+    record_format: Option<RecordFormat>,
+    compression: Option<Compression>,
+    heartbeat_interval_ms: Option<u64>,
+    heartbeat_timeout_ms: Option<u64>,
+}
+
+impl JobConfig {
+    /// Builds the job settings from the program arguments (without the program
+    /// name), reporting every problem found at once.
+    pub fn from_args(args: &[String]) -> Result<Self> {
+        if args.is_empty() {
+            return Err(Error::EmptyArguments);
+        }
+        let mut problems = Vec::new();
+        let mut cli = JobFile::default();
+        let mut config_file = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let flag = arg.as_str();
+            if !flag.starts_with('-') {
+                cli.inputs.push(arg.clone());
+                continue;
+            }
+            let switch = match flag {
+                "--keep-scratch" => Some((&mut cli.keep_scratch, true)),
+                "--follow-symlinks" => Some((&mut cli.follow_symlinks, true)),
+                "--hidden" => Some((&mut cli.hidden, true)),
+                "--no-merge" => Some((&mut cli.merge, false)),
+                "--processes" => Some((&mut cli.processes, true)),
+                _ => None,
+            };
+            if let Some((setting, value)) = switch {
+                *setting = Some(value);
+                continue;
+            }
+            let Some(value) = args.next() else {
+                problems.push(format!("{} expects a value", flag));
+                break;
+            };
+            match flag {
+                "-c" | "--config" => config_file = Some(PathBuf::from(value)),
+                "--include" => cli.include.push(value.clone()),
+                "--exclude" => cli.exclude.push(value.clone()),
+                "--input-format" => cli.input_format = parse_flag(flag, value, &mut problems),
+                "--invalid-utf8" => cli.invalid_utf8 = parse_flag(flag, value, &mut problems),
+                "-o" | "--output" => cli.output = Some(PathBuf::from(value)),
+                "--output-format" => cli.output_format = parse_flag(flag, value, &mut problems),
+                "-s" | "--scratch" => cli.scratch = Some(PathBuf::from(value)),
+                "-m" | "--mappers" => cli.mappers = parse_flag(flag, value, &mut problems),
+                "-r" | "--reducers" => cli.reducers = parse_flag(flag, value, &mut problems),
+                "--split-size" => cli.split_size = parse_flag(flag, value, &mut problems),
+                "--flush-every" => cli.flush_every = parse_flag(flag, value, &mut problems),
+                "--partitioner" => cli.partitioner = parse_flag(flag, value, &mut problems),
+                "--max-retries" => cli.max_retries = parse_flag(flag, value, &mut problems),
+                "--on-failure" => cli.on_failure = parse_flag(flag, value, &mut problems),
+                "--record-format" => cli.record_format = parse_flag(flag, value, &mut problems),
+                "--compression" => cli.compression = parse_flag(flag, value, &mut problems),
+                "--heartbeat-interval" => {
+                    cli.heartbeat_interval_ms = parse_flag(flag, value, &mut problems)
+                }
+                "--heartbeat-timeout" => {
+                    cli.heartbeat_timeout_ms = parse_flag(flag, value, &mut problems)
+                }
+                _ => problems.push(format!("unknown option {}", flag)),
+            }
+        }
+
+        let file = match config_file {
+            Some(path) => read_job_file(&path).unwrap_or_else(|problem| {
+                problems.push(problem);
+                JobFile::default()
+            }),
+            None => JobFile::default(),
+        };
+
+        let config = JobConfig {
+            inputs: if cli.inputs.is_empty() {
+                file.inputs
+            } else {
+                cli.inputs
+            },
+            include: if cli.include.is_empty() {
+                file.include
+            } else {
+                cli.include
+            },
+            exclude: if cli.exclude.is_empty() {
+                file.exclude
+            } else {
+                cli.exclude
+            },
+            follow_symlinks: cli
+                .follow_symlinks
+                .or(file.follow_symlinks)
+                .unwrap_or(false),
+            hidden: cli.hidden.or(file.hidden).unwrap_or(false),
+            input_format: cli
+                .input_format
+                .or(file.input_format)
+                .unwrap_or(InputFormatKind::Lines),
+            invalid_utf8: cli.invalid_utf8.or(file.invalid_utf8).unwrap_or_default(),
+            output_dir: cli
+                .output
+                .or(file.output)
+                .unwrap_or_else(|| PathBuf::from("./output")),
+            output_format: cli
+                .output_format
+                .or(file.output_format)
+                .unwrap_or(OutputFormatKind::Tsv),
+            merge: cli.merge.or(file.merge).unwrap_or(true),
+            scratch_dir: cli
+                .scratch
+                .or(file.scratch)
+                .unwrap_or_else(|| std::env::temp_dir().join("tinymapreduce")),
+            keep_scratch: cli.keep_scratch.or(file.keep_scratch).unwrap_or(false),
+            processes: cli.processes.or(file.processes).unwrap_or(false),
+            mappers: cli.mappers.or(file.mappers).unwrap_or_else(num_cpus::get),
+            reducers: cli.reducers.or(file.reducers).unwrap_or_else(num_cpus::get),
+            split_bytes: cli.split_size.or(file.split_size).unwrap_or(1 << 20),
+            flush_every: cli.flush_every.or(file.flush_every).unwrap_or(10),
+            partitioner: cli
+                .partitioner
+                .or(file.partitioner)
+                .unwrap_or(PartitionerKind::Hash),
+            max_retries: cli.max_retries.or(file.max_retries).unwrap_or(2),
+            on_failure: cli
+                .on_failure
+                .or(file.on_failure)
+                .unwrap_or(FailurePolicy::Fail),
+            record_format: cli
+                .record_format
+                .or(file.record_format)
+                .unwrap_or(RecordFormat::Binary),
+            compression: cli
+                .compression
+                .or(file.compression)
+                .unwrap_or(Compression::None),
+            heartbeat_interval: Duration::from_millis(
+                cli.heartbeat_interval_ms
+                    .or(file.heartbeat_interval_ms)
+                    .unwrap_or(1000),
+            ),
+            heartbeat_timeout: Duration::from_millis(
+                cli.heartbeat_timeout_ms
+                    .or(file.heartbeat_timeout_ms)
+                    .unwrap_or(10_000),
+            ),
+        };
+        problems.extend(config.validate());
+
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(Error::InvalidArguments(problems))
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.inputs.is_empty() {
+            problems.push(String::from("no input given"));
+        }
+        for input in self.inputs.iter() {
+            if let Err(e) = glob::Pattern::new(input) {
+                problems.push(format!("invalid input pattern {:?}: {}", input, e));
+            }
+        }
+        for pattern in self.include.iter().chain(self.exclude.iter()) {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                problems.push(format!("invalid filter pattern {:?}: {}", pattern, e));
+            }
+        }
+        if self.mappers == 0 {
+            problems.push(String::from("at least one mapper is needed"));
+        }
+        if self.reducers == 0 {
+            problems.push(String::from("at least one reducer is needed"));
+        }
+        if self.split_bytes == 0 {
+            problems.push(String::from("--split-size must be at least 1"));
+        }
+        if self.flush_every == 0 {
+            problems.push(String::from("--flush-every must be at least 1"));
+        }
+        if self.compression != Compression::None && self.record_format == RecordFormat::Text {
+            problems.push(String::from(
+                "only the binary record format can be compressed",
+            ));
+        }
+        if self.heartbeat_interval.is_zero() {
+            problems.push(String::from("--heartbeat-interval must be at least 1"));
+        }
+        if self.heartbeat_timeout <= self.heartbeat_interval {
+            problems.push(String::from(
+                "--heartbeat-timeout must be longer than --heartbeat-interval",
+            ));
+        }
+        if self.output_dir == self.scratch_dir {
+            problems.push(String::from(
+                "the output and scratch directories must differ",
+            ));
+        }
+        problems
+    }
+
+    /// Resolves the inputs into the list of files to map. Directories are
+    /// walked recursively and filtered, glob patterns contribute every path
+    /// they match.
+    pub fn input_files(&self) -> Result<Vec<PathBuf>> {
+        input::discover(&self.inputs, &self.input_filter())
+    }
+
+    /// The format the results are written in.
+    pub fn output_format(&self) -> Arc<dyn OutputFormat> {
+        match self.output_format {
+            OutputFormatKind::Tsv => Arc::new(output::Tsv),
+            OutputFormatKind::Csv => Arc::new(output::Csv),
+            OutputFormatKind::Jsonl => Arc::new(output::JsonLines),
+        }
+    }
+
+    fn input_filter(&self) -> InputFilter {
+        // patterns were validated up front
+        let patterns = |patterns: &[String]| {
+            patterns
+                .iter()
+                .filter_map(|pattern| glob::Pattern::new(pattern).ok())
+                .collect()
+        };
+        InputFilter {
+            include: patterns(&self.include),
+            exclude: patterns(&self.exclude),
+            follow_symlinks: self.follow_symlinks,
+            hidden: self.hidden,
+        }
+    }
+}
+
+fn parse_flag<T: FromStr>(flag: &str, value: &str, problems: &mut Vec<String>) -> Option<T> {
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(_) => {
+            problems.push(format!("invalid value {:?} for {}", value, flag));
+            None
+        }
+    }
+}
+
+fn read_job_file(path: &Path) -> std::result::Result<JobFile, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    toml::from_str(&contents).map_err(|e| format!("invalid job file {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = JobConfig::from_args(&args(&[
+            "books",
+            "-o",
+            "out",
+            "--mappers",
+            "3",
+            "--reducers",
+            "2",
+            "--flush-every",
+            "5",
+            "--partitioner",
+            "range",
+            "--include",
+            "*.txt",
+            "--hidden",
+            "--invalid-utf8",
+            "replace",
+            "--output-format",
+            "jsonl",
+            "--no-merge",
+            "--processes",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config,
+            JobConfig {
+                inputs: vec![String::from("books")],
+                include: vec![String::from("*.txt")],
+                exclude: Vec::new(),
+                follow_symlinks: false,
+                hidden: true,
+                input_format: InputFormatKind::Lines,
+                invalid_utf8: InvalidUtf8::Replace,
+                output_dir: PathBuf::from("out"),
+                output_format: OutputFormatKind::Jsonl,
+                merge: false,
+                scratch_dir: std::env::temp_dir().join("tinymapreduce"),
+                keep_scratch: false,
+                processes: true,
+                mappers: 3,
+                reducers: 2,
+                split_bytes: 1 << 20,
+                flush_every: 5,
+                partitioner: PartitionerKind::Range,
+                max_retries: 2,
+                on_failure: FailurePolicy::Fail,
+                record_format: RecordFormat::Binary,
+                compression: Compression::None,
+                heartbeat_interval: Duration::from_secs(1),
+                heartbeat_timeout: Duration::from_secs(10),
+            }
+        );
+    }
+
+    #[test]
+    fn test_from_args_reports_every_problem() {
+        let result = JobConfig::from_args(&args(&["-m", "zero", "-r", "0", "--bogus", "x"]));
+        match result {
+            Err(Error::InvalidArguments(problems)) => {
+                assert_eq!(
+                    problems,
+                    vec![
+                        String::from("invalid value \"zero\" for -m"),
+                        String::from("unknown option --bogus"),
+                        String::from("no input given"),
+                        String::from("at least one reducer is needed"),
+                    ]
+                );
+            }
+            other => panic!("expected invalid arguments, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_job_file() {
+        let path = std::env::temp_dir().join(format!("job-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "inputs = [\"books/*.txt\"]\n\
+             reducers = 4\n\
+             scratch = \"/tmp/scratch\"\n\
+             keep_scratch = true\n\
+             on_failure = \"skip\"\n\
+             input_format = \"whole\"\n\
+             heartbeat_interval_ms = 100\n\
+             heartbeat_timeout_ms = 500\n",
+        )
+        .unwrap();
+
+        let config =
+            JobConfig::from_args(&args(&["-c", path.to_str().unwrap(), "-r", "8"])).unwrap();
+        assert_eq!(config.inputs, vec![String::from("books/*.txt")]);
+        assert_eq!(config.reducers, 8);
+        assert_eq!(config.scratch_dir, PathBuf::from("/tmp/scratch"));
+        assert!(config.keep_scratch);
+        assert_eq!(config.on_failure, FailurePolicy::Skip);
+        assert_eq!(config.input_format, InputFormatKind::Whole);
+        assert_eq!(config.heartbeat_timeout, Duration::from_millis(500));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
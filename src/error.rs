@@ -1,5 +1,8 @@
 use std::fmt;
 use std::error::Error as StdError;
+use std::path::PathBuf;
+
+use crate::input::InputSplit;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -8,6 +11,16 @@ pub enum Error {
     EmptyArguments,
     InvalidArguments(Vec<String>), // Contains invalid arguments but does not contain an underlying error
     DirectoryReadError(std::io::Error),
+    Io(std::io::Error),
+    InputError(PathBuf, std::io::Error), // An input file that could not be opened or read
+    ActorGone(&'static str), // The actor behind a handle stopped before answering
+    PartitionOutOfRange { partition: usize, partitions: usize },
+    WriteRejected(PathBuf), // The writer refused a write, e.g. for a file it never began writing
+    TaskFailed { input: InputSplit, attempts: usize, source: Box<Error> }, // A map task that ran out of retries
+    BadRecord(String), // A record left out of its split, and why it could not be mapped
+    Protocol(String), // A coordinator or worker sent something it should not have, or could not be decoded
+    Remote(String), // A task failed on a worker, with the worker's error message
+    FetchFailed { addr: String, message: String }, // A shuffle server that could not be fetched from, after retries
     CoreError,
 }
 
@@ -17,6 +30,26 @@ impl fmt::Display for Error {
             Error::EmptyArguments => write!(f, "No arguments provided"),
             Error::InvalidArguments(ref args) => write!(f, "Invalid arguments provided: {:?}", args),
             Error::DirectoryReadError(ref e) => write!(f, "Error reading directory: {}", e),
+            Error::Io(ref e) => write!(f, "I/O error: {}", e),
+            Error::InputError(ref path, ref e) => write!(f, "Error reading {}: {}", path.display(), e),
+            Error::ActorGone(actor) => write!(f, "The {} actor is gone", actor),
+            Error::PartitionOutOfRange { partition, partitions } => write!(
+                f,
+                "Partitioner chose partition {} but the job only has {}",
+                partition, partitions
+            ),
+            Error::WriteRejected(ref path) => write!(f, "Writer rejected a write to {}", path.display()),
+            Error::TaskFailed { ref input, attempts, ref source } => write!(
+                f,
+                "Mapping {} failed after {} attempt(s): {}",
+                input,
+                attempts,
+                source
+            ),
+            Error::BadRecord(ref problem) => write!(f, "Left out a bad record: {}", problem),
+            Error::Protocol(ref problem) => write!(f, "Protocol error: {}", problem),
+            Error::Remote(ref message) => write!(f, "Worker failed: {}", message),
+            Error::FetchFailed { ref addr, ref message } => write!(f, "Fetching from {} failed: {}", addr, message),
             Error::CoreError => write!(f, "An error occurred in the core module"),
         }
     }
@@ -26,7 +59,15 @@ impl StdError for Error {
     fn source(&self) -> Option<&(dyn StdError + 'static)> {
         match *self {
             Error::DirectoryReadError(ref e) => Some(e),
+            Error::Io(ref e) => Some(e),
+            Error::InputError(_, ref e) => Some(e),
+            Error::TaskFailed { ref source, .. } => Some(source.as_ref()),
             _ => None,         }
     }
 }
 
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
@@ -0,0 +1,380 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, Result};
+
+pub mod format;
+pub use self::format::{AsText, Csv, InputFormat, InvalidUtf8, JsonLines, Lines, WholeFile};
+
+/// Decides which of the files found by walking an input directory are mapped.
+/// Files given directly, or matched by an input pattern, are always mapped
+/// unless they are empty.
+#[derive(Debug, Clone, Default)]
+pub struct InputFilter {
+    /// Only files whose path below their input directory matches one of these
+    /// are mapped. No patterns means every file is.
+    pub include: Vec<Pattern>,
+    /// Files whose path below their input directory matches one of these are not mapped.
+    pub exclude: Vec<Pattern>,
+    /// Whether symlinks are followed while walking, rather than skipped.
+    pub follow_symlinks: bool,
+    /// Whether files and directories whose name starts with a dot are walked.
+    pub hidden: bool,
+}
+
+impl InputFilter {
+    fn accepts(&self, relative: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.matches_path(relative)))
+            && !self.exclude.iter().any(|p| p.matches_path(relative))
+    }
+}
+
+/// Expands every input (a file, a directory walked recursively, or a glob
+/// pattern) into the files to map, in a stable order and each file only once,
+/// however many inputs lead to it.
+pub fn discover(inputs: &[String], filter: &InputFilter) -> Result<Vec<PathBuf>> {
+    let mut walk = Walk {
+        filter,
+        files: Vec::new(),
+        seen: HashSet::new(),
+        visited: HashSet::new(),
+    };
+    let mut problems = Vec::new();
+    for input in inputs.iter() {
+        let path = Path::new(input);
+        let matches = if path.exists() {
+            vec![path.to_path_buf()]
+        } else {
+            // patterns were validated up front, so this only fails on unreadable directories
+            glob::glob(input)
+                .map(|paths| paths.filter_map(|path| path.ok()).collect())
+                .unwrap_or_default()
+        };
+        if matches.is_empty() {
+            problems.push(format!("{} does not exist or matches nothing", input));
+        }
+        for path in matches {
+            if path.is_dir() {
+                walk.dir(&path, &path)?;
+            } else {
+                walk.file(path)?;
+            }
+        }
+    }
+    if problems.is_empty() {
+        Ok(walk.files)
+    } else {
+        Err(Error::InvalidArguments(problems))
+    }
+}
+
+struct Walk<'a> {
+    filter: &'a InputFilter,
+    files: Vec<PathBuf>,
+    /// canonical paths of the files found so far
+    seen: HashSet<PathBuf>,
+    /// canonical paths of the directories walked so far, so symlink loops end
+    visited: HashSet<PathBuf>,
+}
+
+impl Walk<'_> {
+    fn dir(&mut self, root: &Path, dir: &Path) -> Result<()> {
+        if !self
+            .visited
+            .insert(dir.canonicalize().map_err(Error::DirectoryReadError)?)
+        {
+            return Ok(());
+        }
+        let mut entries = std::fs::read_dir(dir)
+            .map_err(Error::DirectoryReadError)?
+            .collect::<std::result::Result<Vec<_>, std::io::Error>>()
+            .map_err(Error::DirectoryReadError)?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            if !self.filter.hidden && entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let mut file_type = entry.file_type().map_err(Error::DirectoryReadError)?;
+            if file_type.is_symlink() {
+                if !self.filter.follow_symlinks {
+                    continue;
+                }
+                match std::fs::metadata(&path) {
+                    Ok(target) => file_type = target.file_type(),
+                    // a dangling link
+                    Err(_) => continue,
+                }
+            }
+            if file_type.is_dir() {
+                self.dir(root, &path)?;
+            } else if file_type.is_file() {
+                let relative = path.strip_prefix(root).unwrap_or(&path);
+                if self.filter.accepts(relative) {
+                    self.file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, path: PathBuf) -> Result<()> {
+        let metadata = std::fs::metadata(&path).map_err(|e| Error::InputError(path.clone(), e))?;
+        // an empty file has nothing to map
+        if metadata.len() == 0 {
+            return Ok(());
+        }
+        let canonical = path
+            .canonicalize()
+            .map_err(|e| Error::InputError(path.clone(), e))?;
+        if self.seen.insert(canonical) {
+            self.files.push(path);
+        }
+        Ok(())
+    }
+}
+
+/// A byte range of an input file that is mapped as one task. Splits planned by
+/// `plan_splits` start at the beginning of a line and end after one, so no line
+/// is cut in half.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputSplit {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl InputSplit {
+    /// The whole of `path`, however long it is.
+    pub fn whole(path: PathBuf) -> Self {
+        InputSplit {
+            path,
+            offset: 0,
+            len: u64::MAX,
+        }
+    }
+}
+
+impl fmt::Display for InputSplit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.offset == 0 && self.len == u64::MAX {
+            write!(f, "{}", self.path.display())
+        } else {
+            write!(
+                f,
+                "{} (bytes {}..{})",
+                self.path.display(),
+                self.offset,
+                self.offset + self.len
+            )
+        }
+    }
+}
+
+/// Cuts every file into splits of about `split_bytes`. Each split is stretched
+/// to the end of the line it would otherwise cut, so a split can be longer when
+/// a line is, and a file no longer than `split_bytes` is a single split.
+pub fn plan_splits(files: &[PathBuf], split_bytes: u64) -> Result<Vec<InputSplit>> {
+    let mut splits = Vec::new();
+    for path in files.iter() {
+        let input_error = |e| Error::InputError(path.clone(), e);
+        let file = File::open(path).map_err(input_error)?;
+        let len = file.metadata().map_err(input_error)?.len();
+        let mut reader = BufReader::new(file);
+        let mut offset = 0;
+        while offset < len {
+            let mut end = offset.saturating_add(split_bytes);
+            if end < len {
+                // from the last byte of the split up to and including the next
+                // newline, which is that byte itself if it already ends a line
+                reader.seek(SeekFrom::Start(end - 1)).map_err(input_error)?;
+                let mut rest = Vec::new();
+                let read = reader.read_until(b'\n', &mut rest).map_err(input_error)?;
+                end = (end - 1 + read as u64).min(len);
+            }
+            splits.push(InputSplit {
+                path: path.clone(),
+                offset,
+                len: end.min(len) - offset,
+            });
+            offset = end;
+        }
+    }
+    Ok(splits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for (file, contents) in [
+            ("books/a.txt", "a"),
+            ("books/notes.md", "notes"),
+            ("books/empty.txt", ""),
+            ("books/.draft.txt", "draft"),
+            ("books/.git/b.txt", "b"),
+            ("books/shelf/c.txt", "c"),
+            ("books/shelf/deep/d.txt", "d"),
+            ("more/e.txt", "e"),
+        ] {
+            let path = dir.join(file);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        dir
+    }
+
+    fn names(dir: &Path, files: Vec<PathBuf>) -> Vec<String> {
+        files
+            .iter()
+            .map(|file| {
+                file.strip_prefix(dir)
+                    .unwrap()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect()
+    }
+
+    fn inputs(dir: &Path, inputs: &[&str]) -> Vec<String> {
+        inputs
+            .iter()
+            .map(|input| dir.join(input).to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_discover_walks_recursively() {
+        let dir = tree("discover-test");
+        let files = discover(
+            &inputs(&dir, &["books", "more/*.txt", "books/a.txt"]),
+            &InputFilter::default(),
+        )
+        .unwrap();
+        // no hidden or empty files, and a.txt only once
+        assert_eq!(
+            names(&dir, files),
+            vec![
+                "books/a.txt",
+                "books/notes.md",
+                "books/shelf/c.txt",
+                "books/shelf/deep/d.txt",
+                "more/e.txt",
+            ]
+        );
+
+        let filter = InputFilter {
+            include: vec![Pattern::new("*.txt").unwrap()],
+            exclude: vec![Pattern::new("shelf/deep/*").unwrap()],
+            hidden: true,
+            ..InputFilter::default()
+        };
+        let files = discover(&inputs(&dir, &["books"]), &filter).unwrap();
+        assert_eq!(
+            names(&dir, files),
+            vec![
+                "books/.draft.txt",
+                "books/.git/b.txt",
+                "books/a.txt",
+                "books/shelf/c.txt",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_discover_symlinks() {
+        let dir = tree("symlink-test");
+        std::os::unix::fs::symlink(dir.join("more"), dir.join("books/more")).unwrap();
+        // a loop back to the top
+        std::os::unix::fs::symlink(dir.join("books"), dir.join("books/shelf/loop")).unwrap();
+        let include = vec![Pattern::new("**/*.txt").unwrap()];
+
+        let filter = InputFilter {
+            include: include.clone(),
+            ..InputFilter::default()
+        };
+        let files = discover(&inputs(&dir, &["books"]), &filter).unwrap();
+        assert_eq!(
+            names(&dir, files),
+            vec!["books/a.txt", "books/shelf/c.txt", "books/shelf/deep/d.txt"]
+        );
+
+        let filter = InputFilter {
+            include,
+            follow_symlinks: true,
+            ..InputFilter::default()
+        };
+        let files = discover(&inputs(&dir, &["books"]), &filter).unwrap();
+        assert_eq!(
+            names(&dir, files),
+            vec![
+                "books/a.txt",
+                "books/more/e.txt",
+                "books/shelf/c.txt",
+                "books/shelf/deep/d.txt",
+            ]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_discover_reports_missing_inputs() {
+        let dir = tree("missing-test");
+        let result = discover(
+            &inputs(&dir, &["books", "nope", "*.csv"]),
+            &InputFilter::default(),
+        );
+        match result {
+            Err(Error::InvalidArguments(problems)) => assert_eq!(problems.len(), 2),
+            other => panic!("expected invalid arguments, got {:?}", other),
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plan_splits() {
+        let dir = std::env::temp_dir().join(format!("split-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let text = dir.join("text.txt");
+        std::fs::write(&text, "one two\nthree\n\nfour five six\nseven").unwrap();
+        let small = dir.join("small.txt");
+        std::fs::write(&small, "tiny\n").unwrap();
+
+        let splits = plan_splits(&[text.clone(), small.clone()], 6).unwrap();
+        let contents = std::fs::read(&text).unwrap();
+        let pieces: Vec<&str> = splits
+            .iter()
+            .filter(|split| split.path == text)
+            .map(|split| {
+                let range = split.offset as usize..(split.offset + split.len) as usize;
+                std::str::from_utf8(&contents[range]).unwrap()
+            })
+            .collect();
+        // every split ends a line, and together they are the whole file
+        assert_eq!(
+            pieces,
+            vec!["one two\n", "three\n", "\nfour five six\n", "seven"]
+        );
+        assert_eq!(
+            splits.last(),
+            Some(&InputSplit {
+                path: small,
+                offset: 0,
+                len: 5
+            })
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
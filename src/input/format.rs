@@ -0,0 +1,531 @@
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use super::InputSplit;
+use crate::{Error, Result};
+
+/// Turns the bytes of an input split into the records a map function takes.
+pub trait InputFormat: Send + Sync + 'static {
+    type Record;
+
+    /// Reads every record of `split`, in order, handing each to `emit`.
+    fn read(&self, split: &InputSplit, emit: &mut dyn FnMut(Self::Record)) -> Result<()>;
+
+    /// Like `read`, but a record that can't be read is handed to `emit` as an
+    /// error and reading goes on with the next one, where the format can tell
+    /// where that starts. Errors it can't read past still fail the split.
+    fn read_skipping(
+        &self,
+        split: &InputSplit,
+        emit: &mut dyn FnMut(Result<Self::Record>),
+    ) -> Result<()> {
+        self.read(split, &mut |record| emit(Ok(record)))
+    }
+
+    /// Whether a file can be cut into line-aligned splits, or has to be read
+    /// as a whole by a single task.
+    fn splittable(&self) -> bool {
+        true
+    }
+}
+
+/// What the text formats do with input that is not valid UTF-8.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum InvalidUtf8 {
+    /// Fail the task with the position of the first bad byte.
+    #[default]
+    Fail,
+    /// Map the record with every bad sequence replaced by U+FFFD.
+    Replace,
+    /// Leave the record out.
+    Skip,
+}
+
+impl FromStr for InvalidUtf8 {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(InvalidUtf8::Fail),
+            "replace" => Ok(InvalidUtf8::Replace),
+            "skip" => Ok(InvalidUtf8::Skip),
+            _ => Err(format!(
+                "unknown invalid UTF-8 policy {:?}, expected fail, replace or skip",
+                s
+            )),
+        }
+    }
+}
+
+impl InvalidUtf8 {
+    /// Decodes a record that starts at byte `offset` of `path`, `None` meaning
+    /// it is left out.
+    fn decode(self, bytes: Vec<u8>, path: &Path, offset: u64) -> Result<Option<String>> {
+        match String::from_utf8(bytes) {
+            Ok(text) => Ok(Some(text)),
+            Err(e) => match self {
+                InvalidUtf8::Fail => Err(invalid_data(
+                    path,
+                    format!(
+                        "invalid UTF-8 at byte {}",
+                        offset + e.utf8_error().valid_up_to() as u64
+                    ),
+                )),
+                InvalidUtf8::Replace => {
+                    Ok(Some(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+                }
+                InvalidUtf8::Skip => Ok(None),
+            },
+        }
+    }
+}
+
+fn invalid_data(path: &Path, error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::InputError(
+        path.to_path_buf(),
+        io::Error::new(io::ErrorKind::InvalidData, error),
+    )
+}
+
+fn open(split: &InputSplit) -> Result<io::Take<BufReader<File>>> {
+    let input_error = |e| Error::InputError(split.path.clone(), e);
+    let mut file = File::open(&split.path).map_err(input_error)?;
+    file.seek(SeekFrom::Start(split.offset))
+        .map_err(input_error)?;
+    Ok(BufReader::new(file).take(split.len))
+}
+
+/// Calls `f` with the offset and text of every line of `split`, without its
+/// line ending.
+fn each_line(
+    split: &InputSplit,
+    invalid_utf8: InvalidUtf8,
+    f: &mut dyn FnMut(u64, String) -> Result<()>,
+) -> Result<()> {
+    let mut reader = open(split)?;
+    let mut offset = split.offset;
+    loop {
+        let mut line = Vec::new();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|e| Error::InputError(split.path.clone(), e))?;
+        if read == 0 {
+            return Ok(());
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+            if line.ends_with(b"\r") {
+                line.pop();
+            }
+        }
+        if let Some(line) = invalid_utf8.decode(line, &split.path, offset)? {
+            f(offset, line)?;
+        }
+        offset += read as u64;
+    }
+}
+
+/// Every line of text is a record.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Lines {
+    pub invalid_utf8: InvalidUtf8,
+}
+
+impl InputFormat for Lines {
+    type Record = String;
+
+    fn read(&self, split: &InputSplit, emit: &mut dyn FnMut(String)) -> Result<()> {
+        each_line(split, self.invalid_utf8, &mut |_, line| {
+            emit(line);
+            Ok(())
+        })
+    }
+}
+
+/// A whole file is a single record. Files are never split.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WholeFile {
+    pub invalid_utf8: InvalidUtf8,
+}
+
+impl InputFormat for WholeFile {
+    type Record = String;
+
+    fn read(&self, split: &InputSplit, emit: &mut dyn FnMut(String)) -> Result<()> {
+        let mut contents = Vec::new();
+        open(split)?
+            .read_to_end(&mut contents)
+            .map_err(|e| Error::InputError(split.path.clone(), e))?;
+        if let Some(contents) = self
+            .invalid_utf8
+            .decode(contents, &split.path, split.offset)?
+        {
+            emit(contents);
+        }
+        Ok(())
+    }
+
+    fn splittable(&self) -> bool {
+        false
+    }
+}
+
+/// Every row of a CSV file is a record of its fields. Files are never split,
+/// since a quoted field may hold a line break, and the header row (if the
+/// file has one) is not a record.
+#[derive(Debug, Clone, Copy)]
+pub struct Csv {
+    pub has_headers: bool,
+    pub delimiter: u8,
+    pub invalid_utf8: InvalidUtf8,
+}
+
+impl Default for Csv {
+    fn default() -> Self {
+        Csv {
+            has_headers: true,
+            delimiter: b',',
+            invalid_utf8: InvalidUtf8::Fail,
+        }
+    }
+}
+
+impl InputFormat for Csv {
+    type Record = Vec<String>;
+
+    fn read(&self, split: &InputSplit, emit: &mut dyn FnMut(Vec<String>)) -> Result<()> {
+        // without skipping, a bad row fails the split before it gets here
+        self.read_rows(split, false, &mut |row| {
+            if let Ok(row) = row {
+                emit(row)
+            }
+        })
+    }
+
+    fn read_skipping(
+        &self,
+        split: &InputSplit,
+        emit: &mut dyn FnMut(Result<Vec<String>>),
+    ) -> Result<()> {
+        self.read_rows(split, true, emit)
+    }
+
+    fn splittable(&self) -> bool {
+        false
+    }
+}
+
+impl Csv {
+    /// Reads every row of `split`. A row that can't be parsed fails the split,
+    /// unless `skip` is set and it is handed to `emit` instead; a failed read
+    /// always does.
+    fn read_rows(
+        &self,
+        split: &InputSplit,
+        skip: bool,
+        emit: &mut dyn FnMut(Result<Vec<String>>),
+    ) -> Result<()> {
+        let reader = csv::ReaderBuilder::new()
+            .has_headers(self.has_headers)
+            .delimiter(self.delimiter)
+            .from_reader(open(split)?);
+        'rows: for row in reader.into_byte_records() {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) if skip && !e.is_io_error() => {
+                    emit(Err(invalid_data(&split.path, e)));
+                    continue;
+                }
+                Err(e) => return Err(invalid_data(&split.path, e)),
+            };
+            let offset = split.offset + row.position().map_or(0, |position| position.byte());
+            let mut fields = Vec::with_capacity(row.len());
+            for field in row.iter() {
+                match self
+                    .invalid_utf8
+                    .decode(field.to_vec(), &split.path, offset)?
+                {
+                    Some(field) => fields.push(field),
+                    None => continue 'rows,
+                }
+            }
+            emit(Ok(fields));
+        }
+        Ok(())
+    }
+}
+
+/// Every line is a JSON document, deserialized into a `T`. Blank lines are
+/// ignored.
+pub struct JsonLines<T> {
+    pub invalid_utf8: InvalidUtf8,
+    records: PhantomData<fn() -> T>,
+}
+
+impl<T> JsonLines<T> {
+    pub fn new(invalid_utf8: InvalidUtf8) -> Self {
+        JsonLines {
+            invalid_utf8,
+            records: PhantomData,
+        }
+    }
+}
+
+impl<T: DeserializeOwned + 'static> InputFormat for JsonLines<T> {
+    type Record = T;
+
+    fn read(&self, split: &InputSplit, emit: &mut dyn FnMut(T)) -> Result<()> {
+        each_line(split, self.invalid_utf8, &mut |offset, line| {
+            if let Some(record) = parse_json_line(split, offset, &line) {
+                emit(record?);
+            }
+            Ok(())
+        })
+    }
+
+    fn read_skipping(&self, split: &InputSplit, emit: &mut dyn FnMut(Result<T>)) -> Result<()> {
+        // every line is a document of its own, so a bad one ends where the next begins
+        each_line(split, self.invalid_utf8, &mut |offset, line| {
+            if let Some(record) = parse_json_line(split, offset, &line) {
+                emit(record);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// The document on the line at `offset` of `split`, `None` for a blank line.
+fn parse_json_line<T: DeserializeOwned>(
+    split: &InputSplit,
+    offset: u64,
+    line: &str,
+) -> Option<Result<T>> {
+    if line.trim().is_empty() {
+        return None;
+    }
+    Some(serde_json::from_str(line).map_err(|e| {
+        invalid_data(
+            &split.path,
+            format!("invalid JSON at byte {}: {}", offset, e),
+        )
+    }))
+}
+
+/// Records that can be read as text, for jobs that map text.
+pub trait IntoText {
+    fn into_text(self) -> String;
+}
+
+impl IntoText for String {
+    fn into_text(self) -> String {
+        self
+    }
+}
+
+/// The fields of a CSV row, separated by spaces.
+impl IntoText for Vec<String> {
+    fn into_text(self) -> String {
+        self.join(" ")
+    }
+}
+
+/// Every string in a JSON document, wherever it is nested, separated by spaces.
+/// Object keys, numbers and the like are left out.
+impl IntoText for serde_json::Value {
+    fn into_text(self) -> String {
+        fn strings(value: serde_json::Value, text: &mut Vec<String>) {
+            match value {
+                serde_json::Value::String(string) => text.push(string),
+                serde_json::Value::Array(values) => {
+                    values.into_iter().for_each(|value| strings(value, text))
+                }
+                serde_json::Value::Object(fields) => fields
+                    .into_iter()
+                    .for_each(|(_, value)| strings(value, text)),
+                _ => {}
+            }
+        }
+        let mut text = Vec::new();
+        strings(self, &mut text);
+        text.join(" ")
+    }
+}
+
+/// Reads the records of another format as text, so that a job mapping lines,
+/// like the word count, can read CSV or JSON Lines input as well.
+pub struct AsText<F>(pub F);
+
+impl<F> InputFormat for AsText<F>
+where
+    F: InputFormat,
+    F::Record: IntoText,
+{
+    type Record = String;
+
+    fn read(&self, split: &InputSplit, emit: &mut dyn FnMut(String)) -> Result<()> {
+        self.0.read(split, &mut |record| emit(record.into_text()))
+    }
+
+    fn read_skipping(
+        &self,
+        split: &InputSplit,
+        emit: &mut dyn FnMut(Result<String>),
+    ) -> Result<()> {
+        self.0
+            .read_skipping(split, &mut |record| emit(record.map(IntoText::into_text)))
+    }
+
+    fn splittable(&self) -> bool {
+        self.0.splittable()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(name: &str, contents: &[u8]) -> InputSplit {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        InputSplit::whole(path)
+    }
+
+    fn read_all<F: InputFormat>(format: &F, split: &InputSplit) -> Result<Vec<F::Record>> {
+        let mut records = Vec::new();
+        format.read(split, &mut |record| records.push(record))?;
+        Ok(records)
+    }
+
+    fn skipping<F: InputFormat>(format: &F, split: &InputSplit) -> Result<Vec<Result<F::Record>>> {
+        let mut records = Vec::new();
+        format.read_skipping(split, &mut |record| records.push(record))?;
+        Ok(records)
+    }
+
+    #[test]
+    fn test_lines() {
+        let split = input("lines-format", b"one\r\ntwo \xff\nthree");
+        let lines = |invalid_utf8| read_all(&Lines { invalid_utf8 }, &split);
+        assert_eq!(
+            lines(InvalidUtf8::Replace).unwrap(),
+            vec!["one", "two \u{fffd}", "three"]
+        );
+        assert_eq!(lines(InvalidUtf8::Skip).unwrap(), vec!["one", "three"]);
+        match lines(InvalidUtf8::Fail) {
+            Err(Error::InputError(_, e)) => assert_eq!(e.to_string(), "invalid UTF-8 at byte 9"),
+            other => panic!("expected an input error, got {:?}", other),
+        }
+        // a split only sees its own lines
+        let second = InputSplit {
+            offset: 5,
+            len: 6,
+            ..split.clone()
+        };
+        assert_eq!(
+            read_all(&Lines::default(), &second)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "Error reading {}: invalid UTF-8 at byte 9",
+                split.path.display()
+            )
+        );
+        std::fs::remove_file(&split.path).unwrap();
+    }
+
+    #[test]
+    fn test_whole_file() {
+        let split = input("whole-format", b"one\ntwo\n");
+        assert_eq!(
+            read_all(&WholeFile::default(), &split).unwrap(),
+            vec!["one\ntwo\n"]
+        );
+        std::fs::remove_file(&split.path).unwrap();
+    }
+
+    #[test]
+    fn test_csv() {
+        let split = input(
+            "csv-format",
+            b"title,words\n\"Moby, Dick\",\"call\nme\"\nEmma,3\n",
+        );
+        assert_eq!(
+            read_all(&Csv::default(), &split).unwrap(),
+            vec![vec!["Moby, Dick", "call\nme"], vec!["Emma", "3"]]
+        );
+
+        // a row with a field too many is left out, the rest are still read
+        std::fs::write(&split.path, b"title,words\nEmma,3,4\nPersuasion,5\n").unwrap();
+        assert!(read_all(&Csv::default(), &split).is_err());
+        let rows = skipping(&Csv::default(), &split).unwrap();
+        assert_eq!(rows.len(), 2);
+        assert!(matches!(rows[0], Err(Error::InputError(..))));
+        assert_eq!(rows[1].as_ref().unwrap(), &vec!["Persuasion", "5"]);
+        std::fs::remove_file(&split.path).unwrap();
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Request {
+        request_id: String,
+        title: String,
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let split = input(
+            "jsonl-format",
+            b"{\"request_id\": \"a\", \"title\": \"first\"}\n\n{\"request_id\": \"b\", \"title\": \"second\"}\n",
+        );
+        let format = JsonLines::<Request>::new(InvalidUtf8::Fail);
+        let requests = read_all(&format, &split).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].request_id, "b");
+        assert_eq!(requests[1].title, "second");
+
+        std::fs::write(&split.path, b"{\"request_id\": \"a\"}\n").unwrap();
+        assert!(matches!(
+            read_all(&format, &split),
+            Err(Error::InputError(ref path, _)) if *path == split.path
+        ));
+
+        std::fs::write(
+            &split.path,
+            b"{\"request_id\": \"a\"}\n{\"request_id\": \"b\", \"title\": \"second\"}\n",
+        )
+        .unwrap();
+        let requests = skipping(&format, &split).unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(&requests[0], Err(e) if e.to_string().contains("at byte 0")));
+        assert_eq!(requests[1].as_ref().unwrap().request_id, "b");
+        std::fs::remove_file(&split.path).unwrap();
+    }
+
+    #[test]
+    fn test_as_text() {
+        let split = input("csv-text-format", b"title,words\n\"Moby, Dick\",call me\n");
+        let format = AsText(Csv::default());
+        assert!(!format.splittable());
+        assert_eq!(
+            read_all(&format, &split).unwrap(),
+            vec!["Moby, Dick call me"]
+        );
+
+        std::fs::write(
+            &split.path,
+            b"{\"title\": \"Emma\", \"pages\": 474, \"tags\": [\"novel\", {\"lang\": \"en\"}]}\n",
+        )
+        .unwrap();
+        let format = AsText(JsonLines::<serde_json::Value>::new(InvalidUtf8::Fail));
+        assert!(format.splittable());
+        // an object's fields come in the order of their names
+        assert_eq!(read_all(&format, &split).unwrap(), vec!["novel en Emma"]);
+        std::fs::remove_file(&split.path).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+use crate::record::Encode;
+use std::fmt::Display;
+use std::hash::Hash;
+use std::str::FromStr;
+mod wordcount;
+pub use self::wordcount::WordCount;
+
+/// Anything that can be used as an intermediate key. Keys are written to the
+/// partition files with `Display` and read back with `FromStr`.
+///
+/// Partitions are sorted, and results merged, by the bytes of that text rather
+/// than by `Ord`, so numeric keys come out in text order: "10" before "9".
+pub trait Key: Ord + Hash + Clone + Display + FromStr + Send + Sync + 'static {}
+
+impl<T> Key for T where T: Ord + Hash + Clone + Display + FromStr + Send + Sync + 'static {}
+
+/// Anything that can be used as an intermediate value. Values are written with
+/// `Encode` in the binary record format, and as text in the text one.
+pub trait Value: Clone + Display + FromStr + Encode + Send + Sync + 'static {}
+
+impl<T> Value for T where T: Clone + Display + FromStr + Encode + Send + Sync + 'static {}
+
+/// The map half of a job: turns one input record into any number of key/value pairs.
+pub trait MapFn: Send + Sync + 'static {
+    /// The records it maps, as read by the job's `InputFormat`, e.g. a line of text.
+    type Input: 'static;
+    type Key: Key;
+    type Value: Value;
+
+    fn map(&self, input: &Self::Input, emit: &mut dyn FnMut(Self::Key, Self::Value));
+}
+
+/// An optional pre-reduce step for a mapper's buffered output. Before a mapper
+/// drains its buffer, every value it holds for a key is handed to the combiner,
+/// and only what the combiner returns is written to the partitions. It must be
+/// safe to apply any number of times, since the reducer sees the combined
+/// values of many mappers side by side.
+pub trait CombineFn<K, V>: Send + Sync + 'static {
+    fn combine(&self, key: &K, values: Vec<V>) -> Vec<V>;
+}
+
+/// The reduce half of a job: folds every value emitted for a key, across all
+/// mappers, into the final result for that key.
+pub trait ReduceFn: Send + Sync + 'static {
+    type Key: Key;
+    type Value: Value;
+    type Output: Display;
+
+    fn reduce(
+        &self,
+        key: &Self::Key,
+        values: &mut dyn Iterator<Item = Self::Value>,
+    ) -> Self::Output;
+}
@@ -0,0 +1,71 @@
+use super::{CombineFn, MapFn, ReduceFn};
+
+/// Counts lowercased, whitespace separated words.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct WordCount;
+
+impl MapFn for WordCount {
+    type Input = String;
+    type Key = String;
+    type Value = u32;
+
+    fn map(&self, line: &String, emit: &mut dyn FnMut(String, u32)) {
+        for word in line.split_ascii_whitespace() {
+            emit(word.to_lowercase(), 1);
+        }
+    }
+}
+
+impl CombineFn<String, u32> for WordCount {
+    fn combine(&self, _key: &String, values: Vec<u32>) -> Vec<u32> {
+        // a single u32 would overflow on a big enough buffer, so start a new
+        // partial count instead of wrapping
+        let mut counts = vec![0u32];
+        for value in values {
+            let last = counts.last_mut().unwrap();
+            match last.checked_add(value) {
+                Some(sum) => *last = sum,
+                None => counts.push(value),
+            }
+        }
+        counts
+    }
+}
+
+impl ReduceFn for WordCount {
+    type Key = String;
+    type Value = u32;
+    type Output = u64;
+
+    fn reduce(&self, _key: &String, values: &mut dyn Iterator<Item = u32>) -> u64 {
+        values.map(u64::from).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_word_count() {
+        let mut emitted = Vec::new();
+        WordCount.map(&String::from("The cat  saw the\tDOG"), &mut |key, value| {
+            emitted.push((key, value))
+        });
+        let words: Vec<&str> = emitted.iter().map(|(key, _)| key.as_str()).collect();
+        assert_eq!(words, vec!["the", "cat", "saw", "the", "dog"]);
+
+        let total = WordCount.reduce(&String::from("the"), &mut vec![1, 2, 3].into_iter());
+        assert_eq!(total, 6);
+    }
+
+    #[test]
+    fn test_word_count_combine() {
+        let key = String::from("the");
+        assert_eq!(WordCount.combine(&key, vec![1, 1, 1]), vec![3]);
+        assert_eq!(
+            WordCount.combine(&key, vec![u32::MAX - 1, 1, 1]),
+            vec![u32::MAX, 1]
+        );
+    }
+}
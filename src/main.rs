@@ -3,105 +3,1002 @@ mod mapper;
 mod reducer;
 mod writer;
 mod error;
+mod job;
+mod partitioner;
+mod config;
+mod record;
+mod input;
+mod cluster;
+mod transport;
+mod shuffle;
+mod process;
 pub use self::error::{Error, Result};
 
-use std::collections::HashMap;
-use std::future::IntoFuture;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use config::{FailurePolicy, InputFormatKind, JobConfig, PartitionerKind};
+use input::{AsText, Csv, InputSplit, JsonLines, Lines, WholeFile};
+use job::{MapFn, ReduceFn};
+use mapper::{MapJob, SharedInputFormat, SharedPartitioner};
+use partitioner::{HashPartitioner, RangePartitioner};
+use reducer::OutputFormat;
+
+/// Bytes read from the start of every input to pick the range partition boundaries.
+const SAMPLE_BYTES_PER_FILE: u64 = 16 * 1024;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    // ------------------ MAPPER ------------------
-    let args: Vec<String> = std::env::args().collect();
-    let arg = args.get(1).ok_or(Error::EmptyArguments)?;
-    let directory = std::path::Path::new(arg);
-    let files = std::fs::read_dir(directory)
-        .map_err(Error::DirectoryReadError)?
-        .map(|res|
-            res.map(|entry|
-                entry.path()))
-        .collect::<std::result::Result<Vec<_>, std::io::Error>>().map_err(Error::DirectoryReadError)?;
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", config::USAGE);
+        return Ok(());
+    }
+    match args.first().map(String::as_str) {
+        Some("coordinator") => return cluster::run_coordinator(&args[1..]).await,
+        Some("worker") => return cluster::run_worker(&args[1..]).await,
+        Some("child") => return process::run_child(&args[1..]).await,
+        _ => {}
+    }
+    let config = JobConfig::from_args(&args).inspect_err(|_| eprintln!("{}\n", config::USAGE))?;
+    let files = config.input_files()?;
 
     println!("files {:?}", files);
 
-    let writer_handle = writer::WriterHandle::new().await;
+    let map_job = word_count_job(&config, &files);
+    run_job(&config, map_job, Arc::new(job::WordCount), files).await
+}
 
-    let mut unoccupied_mappers: HashMap<usize, mapper::HandleMapper> =
-        HashMap::with_capacity(num_cpus::get());
-    let mut occupied_mappers: HashMap<usize, mapper::HandleMapper> =
-        HashMap::with_capacity(num_cpus::get());
+/// The word count job every mode runs, set up as `config` asks. `files` are
+/// only read to sample keys for the range partitioner.
+fn word_count_job(config: &JobConfig, files: &[PathBuf]) -> MapJob<job::WordCount> {
+    let partitioner: SharedPartitioner<job::WordCount> = match config.partitioner {
+        PartitionerKind::Hash => Arc::new(HashPartitioner::new(config.reducers)),
+        PartitionerKind::Range => Arc::new(RangePartitioner::from_samples(
+            partitioner::sample_keys(
+                &job::WordCount,
+                &*word_count_input(config),
+                files,
+                SAMPLE_BYTES_PER_FILE,
+            ),
+            config.reducers,
+        )),
+    };
+    word_count_job_with(config, partitioner)
+}
+
+/// The word count job with a partitioner that was already set up, e.g. from
+/// the boundaries another process sampled.
+fn word_count_job_with(
+    config: &JobConfig,
+    partitioner: SharedPartitioner<job::WordCount>,
+) -> MapJob<job::WordCount> {
+    let word_count = Arc::new(job::WordCount);
+    let mut map_job = MapJob::new(word_count.clone(), word_count_input(config), partitioner);
+    map_job.combiner = Some(word_count.clone());
+    map_job.flush_every = config.flush_every;
+    map_job.record_format = config.record_format;
+    map_job.skip_bad_records = config.on_failure == FailurePolicy::Skip;
+    map_job
+}
 
-    for id in 0..num_cpus::get() {
-        unoccupied_mappers.insert(id, mapper::HandleMapper::new(writer_handle.clone()));
+/// How the word count job reads its input.
+fn word_count_input(config: &JobConfig) -> SharedInputFormat<job::WordCount> {
+    match config.input_format {
+        InputFormatKind::Lines => Arc::new(Lines {
+            invalid_utf8: config.invalid_utf8,
+        }),
+        InputFormatKind::Whole => Arc::new(WholeFile {
+            invalid_utf8: config.invalid_utf8,
+        }),
+        // the words of every field or string are counted
+        InputFormatKind::Csv => Arc::new(AsText(Csv {
+            invalid_utf8: config.invalid_utf8,
+            ..Csv::default()
+        })),
+        InputFormatKind::Jsonl => Arc::new(AsText(JsonLines::<serde_json::Value>::new(
+            config.invalid_utf8,
+        ))),
     }
+}
 
-    //i will keep two seperate queues, an occupied queue and an unoccupied queue.
-    //in the main loop of the program, i will constantly assign files to be processed
-    //from mappers in the unoccupied queue.
-    let mut counter = 0;
-    loop {
-        println!("counter: {}", counter);
-        let mut tasks = Vec::new();
-        if files.is_empty() {
-            break;
-        }
-        while let Some((id, mapper)) = unoccupied_mappers
+/// Runs a whole job: maps every file into the partitions of a scratch
+/// directory of its own, reduces each partition, and merges the reduced
+/// partitions into a single result file. The scratch directory is removed at
+/// the end, whether the job succeeded or not, unless it is asked to be kept.
+async fn run_job<M, R>(
+    config: &JobConfig,
+    mut map_job: MapJob<M>,
+    reduce_fn: Arc<R>,
+    files: Vec<PathBuf>,
+) -> Result<()>
+where
+    M: MapFn,
+    R: ReduceFn<Key = M::Key, Value = M::Value>,
+{
+    if config.processes {
+        process::check_job::<M>()?;
+        process::check_job::<R>()?;
+    }
+    let scratch_dir = create_scratch_dir(&config.scratch_dir)?;
+    map_job.scratch_dir = scratch_dir.clone();
+    let result = run_phases(config, map_job, reduce_fn, files, &scratch_dir).await;
+    remove_scratch_dir(config, &scratch_dir);
+    result
+}
+
+async fn run_phases<M, R>(
+    config: &JobConfig,
+    map_job: MapJob<M>,
+    reduce_fn: Arc<R>,
+    files: Vec<PathBuf>,
+    scratch_dir: &Path,
+) -> Result<()>
+where
+    M: MapFn,
+    R: ReduceFn<Key = M::Key, Value = M::Value>,
+{
+    let splits = job_splits(config, &map_job, &files)?;
+    let failures = map_phase(config, map_job, splits).await?;
+    let summaries = reduce_phase(config, reduce_fn, scratch_dir).await?;
+    report_results(config, &summaries, &failures)
+}
+
+/// Cuts the job's input files into the splits that are mapped as tasks.
+fn job_splits<M: MapFn>(
+    config: &JobConfig,
+    map_job: &MapJob<M>,
+    files: &[PathBuf],
+) -> Result<Vec<InputSplit>> {
+    let split_bytes = if map_job.input_format.splittable() {
+        config.split_bytes
+    } else {
+        u64::MAX
+    };
+    let splits = input::plan_splits(files, split_bytes)?;
+    println!("{} files in {} splits", files.len(), splits.len());
+    Ok(splits)
+}
+
+// ------------------ MERGE ------------------
+
+/// Merges the reduced partitions into the result file, unless the job keeps
+/// them apart, and reports what was written and what could not be mapped.
+fn report_results(
+    config: &JobConfig,
+    summaries: &[reducer::ReduceSummary],
+    failures: &[(InputSplit, Error)],
+) -> Result<()> {
+    let keys = summaries.iter().map(|summary| summary.keys).sum::<usize>();
+    let records = summaries.iter().map(|summary| summary.records).sum::<u64>();
+    if config.merge {
+        let mut parts: Vec<_> = summaries
             .iter()
-            .next()
-            .map(|(id, mapper)| (*id, mapper.clone()))
-        {
-            if let Some(file) = files.pop() {
-                unoccupied_mappers.remove(&id);
-                occupied_mappers.insert(id, mapper.clone());
-                let handle = tokio::spawn(async move {
-                    let message_id: usize = mapper.process_file_with_buffer(file).await;
-                    return (message_id, id);
-                });
-                tasks.push(handle);
-            } else {
-                break;
-            }
+            .map(|summary| summary.output.clone())
+            .collect();
+        parts.sort();
+        let output_format = config.output_format();
+        let result_file = config
+            .output_dir
+            .join(format!("result.{}", output_format.extension()));
+        reducer::merge_outputs(&parts, &result_file, &*output_format)?;
+        println!(
+            "wrote {} keys ({} records) to {}",
+            keys,
+            records,
+            result_file.display()
+        );
+    } else {
+        println!(
+            "wrote {} keys ({} records) to {} partition files in {}",
+            keys,
+            records,
+            summaries.len(),
+            config.output_dir.display()
+        );
+    }
+    let (records, splits): (Vec<_>, Vec<_>) = failures
+        .iter()
+        .partition(|(_, e)| matches!(e, Error::BadRecord(_)));
+    if !records.is_empty() {
+        eprintln!("{} bad record(s) were left out:", records.len());
+        for (split, e) in records {
+            eprintln!("  {}: {}", split, e);
         }
-        for task in tasks.iter_mut() {
-            let future_results = task.into_future().await;
-            match future_results {
-                Ok((_, mapper_id)) => {
-                    if let Some(free_worker) = occupied_mappers.remove(&mapper_id) {
-                        unoccupied_mappers.insert(mapper_id, free_worker);
+    }
+    if !splits.is_empty() {
+        eprintln!("{} input split(s) could not be mapped:", splits.len());
+        for (split, e) in splits {
+            eprintln!("  {}: {}", split, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes a job's scratch directory once it is done, unless it is asked to be kept.
+fn remove_scratch_dir(config: &JobConfig, scratch_dir: &Path) {
+    if config.keep_scratch {
+        println!("kept scratch directory {}", scratch_dir.display());
+    } else if let Err(e) = std::fs::remove_dir_all(scratch_dir) {
+        eprintln!("could not remove {}: {}", scratch_dir.display(), e);
+    }
+}
+
+/// Creates a directory under `root` that no other job, in this process or
+/// another, uses.
+fn create_scratch_dir(root: &Path) -> Result<PathBuf> {
+    static NEXT_JOB: AtomicUsize = AtomicUsize::new(0);
+    std::fs::create_dir_all(root).map_err(Error::DirectoryReadError)?;
+    let started = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let dir = root.join(format!(
+        "job-{}-{}-{}",
+        std::process::id(),
+        started,
+        NEXT_JOB.fetch_add(1, Ordering::Relaxed)
+    ));
+    // create_dir rather than create_dir_all, so a name clash is an error
+    std::fs::create_dir(&dir).map_err(Error::DirectoryReadError)?;
+    Ok(dir)
+}
+
+// ------------------ MAPPER ------------------
+
+/// A split waiting to be mapped, with the number of times it has already failed.
+type MapTask = (InputSplit, usize);
+
+/// What a map worker reports back for every task it took: the worker's id, the
+/// task, and the number of splits its mapper now holds unflushed.
+type MapOutcome = (usize, MapTask, Result<(usize, Vec<String>)>);
+
+/// Drain prefixes of the mapper processes that died, whose drains are thrown away.
+type LostDrains = Arc<std::sync::Mutex<Vec<String>>>;
+
+/// Tasks shared by a pool of workers. Whichever worker is idle takes the next
+/// one, so no worker waits on another while tasks remain.
+type TaskQueue<T> = Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<T>>>;
+
+/// Waits for the next task, or `None` once the queue is closed and empty.
+async fn next_task<T>(queue: &TaskQueue<T>) -> Option<T> {
+    queue.lock().await.recv().await
+}
+
+/// Maps every split, returning the splits that were skipped along with why.
+///
+/// Each mapper is driven by a worker that keeps pulling splits from a shared
+/// queue. A failed task is retried up to `config.max_retries` times. A mapper
+/// whose actor died is replaced, and the splits it had buffered but not yet
+/// drained are mapped again. A task that runs out of retries either stops the
+/// job or is skipped and reported, depending on `config.on_failure`.
+async fn map_phase<M: MapFn>(
+    config: &JobConfig,
+    map_job: MapJob<M>,
+    splits: Vec<InputSplit>,
+) -> Result<Vec<(InputSplit, Error)>> {
+    let mut writer_handle =
+        writer::WriterHandle::with_format(config.record_format, config.compression).await;
+    // splits each mapper holds in its buffer, which die with it until it drains
+    let mut buffered: HashMap<usize, Vec<MapTask>> =
+        (0..config.mappers).map(|id| (id, Vec::new())).collect();
+    let mut tasks: Vec<MapTask> = splits.into_iter().map(|split| (split, 0)).collect();
+    let mut failures = Vec::new();
+    let lost_drains = LostDrains::default();
+
+    // every round maps whatever is left with a fresh pool of mappers and drains them
+    // at the end. we only go around again if a mapper died before its final drain.
+    while !tasks.is_empty() {
+        let (task_sender, task_receiver) = mpsc::unbounded_channel();
+        let queue: TaskQueue<MapTask> = Arc::new(tokio::sync::Mutex::new(task_receiver));
+        let (outcome_sender, mut outcomes) = mpsc::unbounded_channel();
+
+        let mut outstanding = tasks.len();
+        for task in tasks.drain(..) {
+            let _ = task_sender.send(task);
+        }
+        let workers: Vec<_> = (0..config.mappers)
+            .map(|id| {
+                tokio::spawn(map_worker(
+                    id,
+                    writer_handle.clone(),
+                    map_job.clone(),
+                    config.processes.then(|| config.clone()),
+                    lost_drains.clone(),
+                    queue.clone(),
+                    outcome_sender.clone(),
+                ))
+            })
+            .collect();
+        drop(outcome_sender);
+
+        while outstanding > 0 {
+            let Some((mapper_id, (split, attempts), result)) = outcomes.recv().await else {
+                return Err(Error::ActorGone("mapper"));
+            };
+            outstanding -= 1;
+            let error = match result {
+                Ok((count, skipped)) => {
+                    note_bad_records(&split, skipped, &mut failures);
+                    let held = buffered.get_mut(&mapper_id).unwrap();
+                    held.push((split, attempts));
+                    // the mapper drained everything but the last `count` splits. a
+                    // mapper process takes what it drained with it when it dies, so
+                    // it holds on to all its splits until its final drain.
+                    if !config.processes {
+                        let drained = held.len().saturating_sub(count);
+                        held.drain(..drained);
                     }
+                    continue;
                 }
-                Err(e) => {
-                    eprintln!("Task failed with error :{}", e)
-                }
+                Err(e) => e,
             };
+            match error {
+                Error::ActorGone("mapper") => {
+                    eprintln!("Mapper {} died, respawning it", mapper_id);
+                    let lost = buffered.get_mut(&mapper_id).unwrap();
+                    outstanding += lost.len();
+                    for task in lost.drain(..) {
+                        let _ = task_sender.send(task);
+                    }
+                }
+                // only errors about the input itself are worth retrying, anything
+                // else (a full disk, a broken partitioner) would just happen again
+                Error::InputError(..) => {}
+                e => return Err(e),
+            }
+
+            if retry_map_task(
+                config,
+                (split, attempts),
+                error,
+                &task_sender,
+                &mut failures,
+            )? {
+                outstanding += 1;
+            }
         }
-        tasks.clear();
-        if files.is_empty() && tasks.is_empty() {
-            for (_, mapper) in unoccupied_mappers.iter() {
-                mapper.cleanup_signal().await;
+
+        // nothing is left to map, closing the queue makes every worker drain its
+        // mapper and stop. a mapper that died in the meantime hands its splits back.
+        drop(task_sender);
+        for (id, worker) in workers.into_iter().enumerate() {
+            match worker.await {
+                Ok(Ok(_)) => buffered.get_mut(&id).unwrap().clear(),
+                Ok(Err(Error::ActorGone("mapper"))) => {
+                    eprintln!("Mapper {} died before draining", id);
+                    tasks.append(buffered.get_mut(&id).unwrap());
+                }
+                Ok(Err(e)) => return Err(e),
+                Err(e) => {
+                    eprintln!("Map worker {} failed with error :{}", id, e);
+                    tasks.append(buffered.get_mut(&id).unwrap());
+                }
             }
+        }
+    }
+    // the reducers must not start before every partition is on disk
+    let written = writer_handle.shutdown().await?;
+    // only now that the writer is done can nothing a dead mapper process drained
+    // still show up, the splits it mapped were all mapped again elsewhere
+    for prefix in lost_drains.lock().unwrap().iter() {
+        discard_drains(&map_job.scratch_dir, prefix)?;
+    }
+    let mut partitions: BTreeMap<PathBuf, writer::FileStats> = BTreeMap::new();
+    for (file, stats) in written {
+        if !file.exists() {
+            // discarded along with the mapper process that drained it
+            continue;
+        }
+        let partition = partitions
+            .entry(file.parent().unwrap_or(&file).to_path_buf())
+            .or_default();
+        partition.bytes += stats.bytes;
+        partition.raw_bytes += stats.raw_bytes;
+        partition.records += stats.records;
+    }
+    let mut total = writer::FileStats::default();
+    for (partition, stats) in partitions.iter() {
+        println!(
+            "{}: {} records, {} bytes ({} uncompressed)",
+            partition.display(),
+            stats.records,
+            stats.bytes,
+            stats.raw_bytes
+        );
+        total.bytes += stats.bytes;
+        total.raw_bytes += stats.raw_bytes;
+    }
+    if total.bytes > 0 {
+        println!(
+            "intermediate data: {} bytes, compression ratio {:.2}",
+            total.bytes,
+            total.raw_bytes as f64 / total.bytes as f64
+        );
+    }
+    Ok(failures)
+}
+
+/// Adds the bad records left out of `split` to `failures`, in place of those
+/// left out of it before if it was mapped again.
+fn note_bad_records(
+    split: &InputSplit,
+    skipped: Vec<String>,
+    failures: &mut Vec<(InputSplit, Error)>,
+) {
+    failures.retain(|(failed, e)| failed != split || !matches!(e, Error::BadRecord(_)));
+    for problem in skipped {
+        eprintln!("Left a bad record of {} out: {}", split, problem);
+        failures.push((split.clone(), Error::BadRecord(problem)));
+    }
+}
+
+/// Sends a map task that failed with `error` back to `queue` while it has
+/// retries left, and returns whether it did. A task out of retries fails the
+/// job, or is skipped and added to `failures`, as `config.on_failure` says.
+fn retry_map_task(
+    config: &JobConfig,
+    (split, mut attempts): MapTask,
+    error: Error,
+    queue: &mpsc::UnboundedSender<MapTask>,
+    failures: &mut Vec<(InputSplit, Error)>,
+) -> Result<bool> {
+    attempts += 1;
+    if attempts <= config.max_retries {
+        eprintln!(
+            "Failed to map {} (attempt {}): {}, retrying",
+            split, attempts, error
+        );
+        let _ = queue.send((split, attempts));
+        return Ok(true);
+    }
+    let failure = Error::TaskFailed {
+        input: split.clone(),
+        attempts,
+        source: Box::new(error),
+    };
+    match config.on_failure {
+        FailurePolicy::Fail => Err(failure),
+        FailurePolicy::Skip => {
+            eprintln!("{}, skipping it", failure);
+            failures.push((split, failure));
+            Ok(false)
+        }
+    }
+}
+
+/// Keeps one mapper busy with tasks from the queue until it is closed, then
+/// drains the mapper. A mapper that dies is replaced straight away; the
+/// scheduler hears about it through the outcome and requeues what it lost.
+/// With `processes`, the job's settings, every mapper runs in a child process,
+/// and the drain prefix of every one that dies is added to `lost_drains`.
+async fn map_worker<M: MapFn>(
+    id: usize,
+    writer_handle: writer::WriterHandle,
+    map_job: MapJob<M>,
+    processes: Option<JobConfig>,
+    lost_drains: LostDrains,
+    queue: TaskQueue<MapTask>,
+    outcomes: mpsc::UnboundedSender<MapOutcome>,
+) -> Result<String> {
+    let spawn = || spawn_mapper(&writer_handle, &map_job, processes.as_ref());
+    // a mapper process has its drains named apart, to throw them away if it dies
+    let lose = |drain_prefix: Option<String>| {
+        lost_drains.lock().unwrap().extend(drain_prefix);
+    };
+    let (mut mapper, mut drain_prefix) = spawn().await?;
+    while let Some(task) = next_task(&queue).await {
+        let result = mapper.process_file_with_buffer(task.0.clone()).await;
+        let died = matches!(result, Err(Error::ActorGone("mapper")));
+        if outcomes.send((id, task, result)).is_err() {
+            // the scheduler gave up on the job
             break;
         }
+        if died {
+            lose(drain_prefix);
+            (mapper, drain_prefix) = spawn().await?;
+        }
+    }
+    let drained = mapper.cleanup_signal().await;
+    if matches!(drained, Err(Error::ActorGone("mapper"))) {
+        lose(drain_prefix);
+    }
+    drained
+}
 
-        counter += 1;
+/// Starts a mapper for `map_job`, as an actor of this process or, given the
+/// job's settings in `processes`, in a child process of its own. A mapper
+/// process also comes with what the names of its drains start with.
+async fn spawn_mapper<M: MapFn>(
+    writer_handle: &writer::WriterHandle,
+    map_job: &MapJob<M>,
+    processes: Option<&JobConfig>,
+) -> Result<(mapper::HandleMapper<M>, Option<String>)> {
+    match processes {
+        Some(config) => process::spawn_mapper(config, map_job, writer_handle)
+            .await
+            .map(|(mapper, drain_prefix)| (mapper, Some(drain_prefix)))
+            .inspect_err(|e| eprintln!("Could not start a mapper process: {}", e)),
+        None => Ok((
+            mapper::HandleMapper::new(writer_handle.clone(), map_job.clone()),
+            None,
+        )),
     }
-    drop(unoccupied_mappers);
-    drop(occupied_mappers);
+}
 
-    // ------------------ REDUCER ------------------
+// ------------------ REDUCER ------------------
+async fn reduce_phase<R: ReduceFn>(
+    config: &JobConfig,
+    reduce_fn: Arc<R>,
+    scratch_dir: &Path,
+) -> Result<Vec<reducer::ReduceSummary>> {
+    let partitions = list_partitions(scratch_dir)?;
+    println!("reducing {} partition(s)", partitions.len());
+    std::fs::create_dir_all(&config.output_dir)?;
+    let (output_format, output_dir) = reduce_output(config, scratch_dir);
 
-    let mut files = std::fs::read_dir("./tmp")?
-        .map(|res| res.unwrap().path())
-        .collect::<Vec<_>>();
-    println!("files: {:?}", files);
+    //same queue as the mappers, every reducer shuffles and reduces partitions
+    //until none are left.
+    let (sender, receiver) = mpsc::unbounded_channel();
+    for partition in partitions {
+        let _ = sender.send(partition);
+    }
+    drop(sender);
+    let queue: TaskQueue<PathBuf> = Arc::new(tokio::sync::Mutex::new(receiver));
 
-    let mut unoccupied_mappers: HashMap<usize, reducer::HandleReducer> =
-        HashMap::with_capacity(num_cpus::get());
-    let mut occupied_mappers: HashMap<usize, reducer::HandleReducer> =
-        HashMap::with_capacity(num_cpus::get());
+    let mut reducers = Vec::new();
+    for _ in 0..config.reducers {
+        reducers.push(spawn_reducer(config, &reduce_fn, scratch_dir, &output_format).await?);
+    }
+    let workers: Vec<_> = reducers
+        .into_iter()
+        .map(|mut reducer| {
+            let queue = queue.clone();
+            let output_dir = output_dir.clone();
+            let config = config.clone();
+            let reduce_fn = reduce_fn.clone();
+            let scratch_dir = scratch_dir.to_path_buf();
+            let output_format = output_format.clone();
+            tokio::spawn(async move {
+                let mut summaries = Vec::new();
+                while let Some(partition) = next_task(&queue).await {
+                    let output = part_file(&output_dir, &partition, output_format.extension());
+                    let mut attempt = 0;
+                    loop {
+                        match reduce_partition(&reducer, &partition, &[], attempt, output.clone()).await {
+                            // a reducer that died, e.g. its process crashed, is
+                            // replaced and the partition reduced again
+                            Err(Error::ActorGone("reducer")) if attempt < config.max_retries => {
+                                attempt += 1;
+                                eprintln!(
+                                    "The reducer of {} is gone, retrying with a new one (attempt {})",
+                                    partition.display(),
+                                    attempt
+                                );
+                                reducer =
+                                    spawn_reducer(&config, &reduce_fn, &scratch_dir, &output_format)
+                                        .await?;
+                            }
+                            result => {
+                                summaries.push(result?);
+                                break;
+                            }
+                        }
+                    }
+                }
+                Ok::<_, Error>(summaries)
+            })
+        })
+        .collect();
 
-    for id in 0..num_cpus::get() {
-        unoccupied_mappers.insert(id, reducer::HandleReducer::new());
+    let mut summaries: Vec<reducer::ReduceSummary> = Vec::new();
+    for worker in workers {
+        match worker.await {
+            Ok(worker_summaries) => summaries.extend(worker_summaries?),
+            // the partition it was reducing would be missing from the results
+            Err(e) => {
+                eprintln!("Reduce worker failed with error :{}", e);
+                return Err(Error::ActorGone("reducer"));
+            }
+        }
     }
 
+    Ok(summaries)
+}
+
+/// A reducer in this process, or in a child process with `--processes`.
+async fn spawn_reducer<R: ReduceFn>(
+    config: &JobConfig,
+    reduce_fn: &Arc<R>,
+    scratch_dir: &Path,
+    output_format: &Arc<dyn OutputFormat>,
+) -> Result<reducer::HandleReducer> {
+    if config.processes {
+        process::check_job::<R>()?;
+        return process::spawn_reducer(config, scratch_dir).await;
+    }
+    Ok(reducer::HandleReducer::with_output_format(
+        reduce_fn.clone(),
+        config.record_format,
+        config.compression,
+        output_format.clone(),
+    ))
+}
+
+/// The partition directories the mappers wrote into `scratch_dir`.
+fn list_partitions(scratch_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut partitions = std::fs::read_dir(scratch_dir)
+        .map_err(Error::DirectoryReadError)?
+        .map(|res| res.map(|entry| entry.path()))
+        .collect::<std::result::Result<Vec<_>, std::io::Error>>()
+        .map_err(Error::DirectoryReadError)?;
+    // only the partition directories, not the sorted runs next to them
+    partitions.retain(|path| path.is_dir());
+    partitions.sort();
+    Ok(partitions)
+}
+
+/// Removes every file drained into the partitions under names starting with `prefix`.
+fn discard_drains(scratch_dir: &Path, prefix: &str) -> Result<()> {
+    for partition in list_partitions(scratch_dir)? {
+        for entry in std::fs::read_dir(&partition).map_err(Error::DirectoryReadError)? {
+            let path = entry.map_err(Error::DirectoryReadError)?.path();
+            if path
+                .file_name()
+                .is_some_and(|name| name.to_string_lossy().starts_with(prefix))
+            {
+                std::fs::remove_file(&path)?;
+            }
+        }
+    }
     Ok(())
 }
+
+/// The format partitions are reduced into and the directory their results go to.
+fn reduce_output(config: &JobConfig, scratch_dir: &Path) -> (Arc<dyn OutputFormat>, PathBuf) {
+    // results that get merged afterwards only need to be readable by the merge
+    if config.merge {
+        (
+            Arc::new(reducer::output::Records),
+            scratch_dir.to_path_buf(),
+        )
+    } else {
+        (config.output_format(), config.output_dir.clone())
+    }
+}
+
+/// Where the result of reducing `partition` is written.
+fn part_file(output_dir: &Path, partition: &Path, extension: &str) -> PathBuf {
+    output_dir.join(format!(
+        "part-{}.{}",
+        partition.file_stem().unwrap_or_default().to_string_lossy(),
+        extension
+    ))
+}
+
+/// Shuffles one partition into a sorted run and reduces it into `output`. The
+/// partition is fetched from the shuffle servers in `sources`, if there are any.
+/// Every `attempt` at a partition sorts and reduces into files of its own, and
+/// its result only takes the place of `output` once it is whole, so an attempt
+/// that was given up on but still runs can't get in the way of the next one.
+async fn reduce_partition(
+    reducer: &reducer::HandleReducer,
+    partition: &Path,
+    sources: &[String],
+    attempt: usize,
+    output: PathBuf,
+) -> Result<reducer::ReduceSummary> {
+    let sorted = reducer
+        .clone()
+        .shuffle_from(
+            partition.to_string_lossy().into_owned(),
+            sources.to_vec(),
+            attempt,
+        )
+        .await?;
+    let mut partial = output.clone().into_os_string();
+    partial.push(format!(".{}.tmp", attempt));
+    let partial = PathBuf::from(partial);
+    let mut summary = reducer
+        .clone()
+        .reduce(sorted.to_string_lossy().into_owned(), partial.clone())
+        .await?;
+    std::fs::rename(&partial, &output)?;
+    summary.output = output;
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use record::RecordReader;
+
+    /// Emits every line as a key, but panics the first `panics` times it sees "boom".
+    struct Flaky {
+        panics: AtomicUsize,
+    }
+
+    impl MapFn for Flaky {
+        type Input = String;
+        type Key = String;
+        type Value = u32;
+
+        fn map(&self, line: &String, emit: &mut dyn FnMut(String, u32)) {
+            if line == "boom" && self.panics.load(Ordering::SeqCst) > 0 {
+                self.panics.fetch_sub(1, Ordering::SeqCst);
+                panic!("flaky map function");
+            }
+            emit(line.to_string(), 1);
+        }
+    }
+
+    fn test_job(
+        name: &str,
+        on_failure: FailurePolicy,
+        panics: usize,
+    ) -> (JobConfig, MapJob<Flaky>, Vec<PathBuf>) {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("scratch")).unwrap();
+        let mut files = Vec::new();
+        for (name, contents) in [("a.txt", "hello"), ("b.txt", "boom"), ("c.txt", "world")] {
+            std::fs::write(dir.join(name), contents).unwrap();
+            files.push(dir.join(name));
+        }
+        let config = JobConfig {
+            inputs: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            follow_symlinks: false,
+            hidden: false,
+            input_format: InputFormatKind::Lines,
+            invalid_utf8: input::InvalidUtf8::Fail,
+            output_dir: dir.join("output"),
+            output_format: config::OutputFormatKind::Tsv,
+            merge: true,
+            scratch_dir: dir.join("scratch"),
+            keep_scratch: false,
+            processes: false,
+            mappers: 1,
+            reducers: 1,
+            split_bytes: 1 << 20,
+            flush_every: 10,
+            partitioner: PartitionerKind::Hash,
+            max_retries: 2,
+            on_failure,
+            record_format: record::RecordFormat::Binary,
+            compression: record::Compression::Lz4,
+            heartbeat_interval: std::time::Duration::from_secs(1),
+            heartbeat_timeout: std::time::Duration::from_secs(10),
+        };
+        let mut map_job = MapJob::new(
+            Arc::new(Flaky {
+                panics: AtomicUsize::new(panics),
+            }),
+            Arc::new(Lines::default()),
+            Arc::new(HashPartitioner::new(1)),
+        );
+        map_job.scratch_dir = config.scratch_dir.clone();
+        map_job.skip_bad_records = on_failure == FailurePolicy::Skip;
+        (config, map_job, files)
+    }
+
+    fn whole(files: Vec<PathBuf>) -> Vec<InputSplit> {
+        files.into_iter().map(InputSplit::whole).collect()
+    }
+
+    fn sorted_partition(config: &JobConfig) -> Vec<String> {
+        let mut lines = Vec::new();
+        for entry in std::fs::read_dir(config.scratch_dir.join("0")).unwrap() {
+            let file = std::fs::File::open(entry.unwrap().path()).unwrap();
+            let reader = RecordReader::new(std::io::BufReader::new(file), config.record_format)
+                .with_compression(config.compression);
+            for record in reader {
+                let (key, value) = record.unwrap();
+                let value: u32 = record::decode_value(config.record_format, &value).unwrap();
+                lines.push(format!("{}:{}", String::from_utf8(key).unwrap(), value));
+            }
+        }
+        lines.sort();
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_map_phase_retries_dead_mapper() {
+        let (config, map_job, files) = test_job("retry-test", FailurePolicy::Fail, 1);
+        let failures = map_phase(&config, map_job, whole(files)).await.unwrap();
+        assert!(failures.is_empty());
+        // the file the dead mapper had buffered is mapped again, and nothing twice
+        assert_eq!(
+            sorted_partition(&config),
+            vec!["boom:1", "hello:1", "world:1"]
+        );
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_map_phase_retries_dead_mapper_after_drain() {
+        let (config, mut map_job, files) = test_job("retry-drain-test", FailurePolicy::Fail, 1);
+        // "hello" is already drained when the mapper dies on "boom"
+        map_job.flush_every = 1;
+        let failures = map_phase(&config, map_job, whole(files)).await.unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(
+            sorted_partition(&config),
+            vec!["boom:1", "hello:1", "world:1"]
+        );
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_processes_only_run_word_count() {
+        let (mut config, map_job, files) = test_job("processes-job-test", FailurePolicy::Fail, 0);
+        config.processes = true;
+        let result = run_job(&config, map_job, Arc::new(job::WordCount), files).await;
+        assert!(matches!(result, Err(Error::InvalidArguments(_))));
+        // turned away before the job made a scratch directory
+        assert!(std::fs::read_dir(&config.scratch_dir)
+            .unwrap()
+            .next()
+            .is_none());
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reduce_partition_attempts_apart() {
+        let (config, map_job, files) = test_job("attempts-test", FailurePolicy::Fail, 0);
+        map_phase(&config, map_job, whole(files)).await.unwrap();
+        let reducer = reducer::HandleReducer::with_output_format(
+            Arc::new(job::WordCount),
+            config.record_format,
+            config.compression,
+            Arc::new(reducer::output::Tsv),
+        );
+        let partition = config.scratch_dir.join("0");
+        let output = config.scratch_dir.join("part-0.tsv");
+        // one attempt was given up on but still runs next to the one after it
+        let (first, second) = tokio::join!(
+            reduce_partition(&reducer, &partition, &[], 1, output.clone()),
+            reduce_partition(&reducer, &partition, &[], 2, output.clone()),
+        );
+        assert_eq!(first.unwrap().output, output);
+        assert_eq!(second.unwrap().output, output);
+        let result = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t1\nworld\t1\n");
+        assert!(config.scratch_dir.join("0.1.sorted").exists());
+        assert!(config.scratch_dir.join("0.2.sorted").exists());
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_map_phase_shares_queue() {
+        let (mut config, map_job, files) = test_job("queue-test", FailurePolicy::Fail, 0);
+        config.mappers = 3;
+        // more files than mappers, so idle mappers have to come back for more
+        let files: Vec<PathBuf> = files.iter().cycle().take(7).cloned().collect();
+        let failures = map_phase(&config, map_job, whole(files)).await.unwrap();
+        assert!(failures.is_empty());
+        assert_eq!(sorted_partition(&config).len(), 7);
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    fn scratch_dirs(config: &JobConfig) -> Vec<PathBuf> {
+        std::fs::read_dir(&config.scratch_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_run_job_owns_scratch_dir() {
+        let (mut config, _, files) = test_job("scratch-test", FailurePolicy::Fail, 0);
+        let map_job = MapJob::new(
+            Arc::new(job::WordCount),
+            Arc::new(Lines::default()),
+            Arc::new(HashPartitioner::new(1)),
+        );
+        // runs sharing a scratch root, one at a time and side by side
+        run_job(
+            &config,
+            map_job.clone(),
+            Arc::new(job::WordCount),
+            files.clone(),
+        )
+        .await
+        .unwrap();
+        let mut other = config.clone();
+        other.output_dir = config.output_dir.with_extension("other");
+        let (first, second) = tokio::join!(
+            run_job(
+                &config,
+                map_job.clone(),
+                Arc::new(job::WordCount),
+                files.clone()
+            ),
+            run_job(
+                &other,
+                map_job.clone(),
+                Arc::new(job::WordCount),
+                files.clone()
+            ),
+        );
+        first.unwrap();
+        second.unwrap();
+        // no run counted another's partitions, and none left anything behind
+        for output_dir in [&config.output_dir, &other.output_dir] {
+            let result = std::fs::read_to_string(output_dir.join("result.tsv")).unwrap();
+            assert_eq!(result, "boom\t1\nhello\t1\nworld\t1\n");
+        }
+        assert!(scratch_dirs(&config).is_empty());
+
+        config.keep_scratch = true;
+        run_job(&config, map_job, Arc::new(job::WordCount), files)
+            .await
+            .unwrap();
+        let kept = scratch_dirs(&config);
+        assert_eq!(kept.len(), 1);
+        assert!(kept[0].join("0").is_dir());
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_job_splits_inputs() {
+        let (mut config, _, mut files) = test_job("split-test", FailurePolicy::Fail, 0);
+        config.mappers = 2;
+        config.split_bytes = 4;
+        let book = config.scratch_dir.with_file_name("book.txt");
+        std::fs::write(&book, "hello world\nhello\nworld hello\n").unwrap();
+        files.push(book);
+        let map_job = MapJob::new(
+            Arc::new(job::WordCount),
+            Arc::new(Lines::default()),
+            Arc::new(HashPartitioner::new(1)),
+        );
+        run_job(&config, map_job, Arc::new(job::WordCount), files)
+            .await
+            .unwrap();
+        // no word was cut in half or counted twice at a split boundary
+        let result = std::fs::read_to_string(config.output_dir.join("result.tsv")).unwrap();
+        assert_eq!(result, "boom\t1\nhello\t4\nworld\t3\n");
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_map_phase_skip_policy() {
+        let (config, map_job, mut files) = test_job("skip-test", FailurePolicy::Skip, usize::MAX);
+        // only the bad record is left out of its split
+        std::fs::write(&files[1], "bang\nboom\nbing").unwrap();
+        // while a file that can't be read at all is left out as a whole
+        files.push(config.scratch_dir.with_file_name("missing.txt"));
+        let failures = map_phase(&config, map_job, whole(files)).await.unwrap();
+        assert_eq!(failures.len(), 2);
+        assert!(failures[0].0.path.ends_with("b.txt"));
+        assert!(matches!(
+            &failures[0].1,
+            Error::BadRecord(problem) if problem.starts_with("record 2: the map function panicked")
+        ));
+        assert!(failures[1].0.path.ends_with("missing.txt"));
+        assert!(matches!(
+            failures[1].1,
+            Error::TaskFailed { attempts: 3, .. }
+        ));
+        assert_eq!(
+            sorted_partition(&config),
+            vec!["bang:1", "bing:1", "hello:1", "world:1"]
+        );
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_map_phase_fail_policy() {
+        let (config, map_job, files) = test_job("fail-test", FailurePolicy::Fail, usize::MAX);
+        let result = map_phase(&config, map_job, whole(files)).await;
+        assert!(matches!(result, Err(Error::TaskFailed { attempts: 3, .. })));
+        std::fs::remove_dir_all(config.scratch_dir.parent().unwrap()).unwrap();
+    }
+}
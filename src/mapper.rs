@@ -1,237 +1,474 @@
-use file_lock::*;
+use crate::input::{InputFormat, InputSplit};
+use crate::job::{CombineFn, MapFn};
+use crate::partitioner::Partitioner;
+use crate::record::{self, Record, RecordFormat};
+use crate::transport::{Call, Transport};
+use crate::writer;
+use crate::writer::Batch;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::BufReader;
+use std::marker::PhantomData;
 use std::ops::DerefMut;
+use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
-use std::str::FromStr;
-use tokio::sync::{mpsc, oneshot, Mutex};
-mod helpers;
-use crate::writer;
-use crate::writer::Request;
-use crate::writer::WriterHandle;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, Mutex};
+
+// numbers the drains of every mapper, so no two drains write the same file
+static NEXT_DRAIN: AtomicUsize = AtomicUsize::new(0);
+
+/// Everything a mapper emitted for one split, grouped by key.
+pub type MapOutput<M> = BTreeMap<<M as MapFn>::Key, Vec<<M as MapFn>::Value>>;
+
+pub type SharedInputFormat<M> = Arc<dyn InputFormat<Record = <M as MapFn>::Input>>;
+
+pub type Combiner<M> = Arc<dyn CombineFn<<M as MapFn>::Key, <M as MapFn>::Value>>;
+
+pub type SharedPartitioner<M> = Arc<dyn Partitioner<<M as MapFn>::Key>>;
+
+/// The parts of a job that every mapper shares.
+pub struct MapJob<M: MapFn> {
+    pub map_fn: Arc<M>,
+    pub input_format: SharedInputFormat<M>,
+    pub partitioner: SharedPartitioner<M>,
+    pub combiner: Option<Combiner<M>>,
+    /// Directory holding a directory per partition, which every drain adds a file
+    /// to. `run_job` points it at the scratch directory the job owns.
+    pub scratch_dir: PathBuf,
+    /// Put in front of the name of every file a drain writes, so that processes
+    /// sharing a scratch directory never write the same file.
+    pub drain_prefix: String,
+    /// Number of splits a mapper buffers before draining them to the partitions.
+    pub flush_every: usize,
+    /// How values are encoded for the writer, which must use the same format.
+    pub record_format: RecordFormat,
+    /// Whether a record the map function panics on, or the input format can't
+    /// read, is left out of its split and reported rather than failing it.
+    pub skip_bad_records: bool,
+}
+
+impl<M: MapFn> MapJob<M> {
+    pub fn new(
+        map_fn: Arc<M>,
+        input_format: SharedInputFormat<M>,
+        partitioner: SharedPartitioner<M>,
+    ) -> Self {
+        Self {
+            map_fn,
+            input_format,
+            partitioner,
+            combiner: None,
+            scratch_dir: std::env::temp_dir().join("tinymapreduce"),
+            drain_prefix: String::new(),
+            flush_every: 10,
+            record_format: RecordFormat::Binary,
+            skip_bad_records: false,
+        }
+    }
+}
 
-pub struct Mapper {
-    receiver: mpsc::Receiver<MapperMessage>,
+// derive(Clone) would needlessly require M: Clone
+impl<M: MapFn> Clone for MapJob<M> {
+    fn clone(&self) -> Self {
+        Self {
+            map_fn: self.map_fn.clone(),
+            input_format: self.input_format.clone(),
+            partitioner: self.partitioner.clone(),
+            combiner: self.combiner.clone(),
+            scratch_dir: self.scratch_dir.clone(),
+            drain_prefix: self.drain_prefix.clone(),
+            flush_every: self.flush_every,
+            record_format: self.record_format,
+            skip_bad_records: self.skip_bad_records,
+        }
+    }
+}
+
+pub struct Mapper<M: MapFn> {
+    receiver: mpsc::Receiver<Call<MapperRequest, MapperResponse>>,
     message_id: Mutex<usize>,
-    internal_buffer: Mutex<Vec<BTreeMap<String, u32>>>,
+    internal_buffer: Mutex<Vec<MapOutput<M>>>,
     writer_handle: writer::WriterHandle,
+    job: MapJob<M>,
 }
 
-pub enum MapperMessage {
-    GetId {
-        respond_to: oneshot::Sender<usize>,
-    },
-    Cleanup {
-        respond_to: oneshot::Sender<String>,
-    },
-    ProcessFileTest {
-        filename: PathBuf,
-        respond_to: oneshot::Sender<String>,
-    },
-    ProcessSingleFile {
-        filename: PathBuf,
-        respond_to: oneshot::Sender<BTreeMap<String, u32>>,
-    },
-    ProcessFileWithBuffer {
-        filename: PathBuf,
-        respond_to: oneshot::Sender<usize>,
-    },
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MapperRequest {
+    GetId,
+    Cleanup,
+    ProcessFileTest { filename: PathBuf },
+    ProcessSingleFile { filename: PathBuf },
+    ProcessFileWithBuffer { split: InputSplit },
 }
 
-impl Mapper {
-    fn new(receiver: mpsc::Receiver<MapperMessage>, writer: writer::WriterHandle) -> Self {
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MapperResponse {
+    Id(usize),
+    CleanedUp(String),
+    Loaded(String),
+    /// The map output of a file, keys and values in their text form.
+    Mapped(Vec<(String, Vec<String>)>),
+    /// Number of splits the mapper now holds in its buffer, and why each bad
+    /// record it left out of the split it just mapped could not be mapped.
+    Buffered(usize, Vec<String>),
+}
+
+impl<M: MapFn> Mapper<M> {
+    fn new(
+        receiver: mpsc::Receiver<Call<MapperRequest, MapperResponse>>,
+        writer: writer::WriterHandle,
+        job: MapJob<M>,
+    ) -> Self {
         Mapper {
             receiver,
             message_id: Mutex::new(0),
             internal_buffer: Mutex::new(Vec::new()),
             writer_handle: writer,
+            job,
         }
     }
 
-    async fn handle_message(&mut self, msg: MapperMessage) {
-        match msg {
-            MapperMessage::GetId { respond_to } => {
+    /// Runs the map function over every record of `split`. Along with the
+    /// output comes why each record that was left out of it was bad, which
+    /// only happens if the job skips bad records.
+    fn map_split(&self, split: &InputSplit) -> Result<(MapOutput<M>, Vec<String>)> {
+        let map_fn = &self.job.map_fn;
+        let mut output: MapOutput<M> = BTreeMap::new();
+        let mut skipped = Vec::new();
+        if !self.job.skip_bad_records {
+            self.job.input_format.read(split, &mut |record| {
+                map_fn.map(&record, &mut |key, value| {
+                    output.entry(key).or_default().push(value);
+                });
+            })?;
+            return Ok((output, skipped));
+        }
+        // what a record emitted only counts once the map function is done with it
+        let mut emitted = Vec::new();
+        let mut records = 0;
+        self.job.input_format.read_skipping(split, &mut |record| {
+            records += 1;
+            let record = match record {
+                Ok(record) => record,
+                Err(e) => {
+                    skipped.push(format!("record {}: {}", records, e));
+                    return;
+                }
+            };
+            let mapped = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                map_fn.map(&record, &mut |key, value| emitted.push((key, value)));
+            }));
+            match mapped {
+                Ok(()) => {
+                    for (key, value) in emitted.drain(..) {
+                        output.entry(key).or_default().push(value);
+                    }
+                }
+                Err(panic) => {
+                    emitted.clear();
+                    skipped.push(format!(
+                        "record {}: the map function panicked: {}",
+                        records,
+                        panic_message(&*panic)
+                    ));
+                }
+            }
+        })?;
+        Ok((output, skipped))
+    }
+
+    async fn handle_message(&mut self, request: MapperRequest) -> Result<MapperResponse> {
+        match request {
+            MapperRequest::GetId => {
                 let mut guard = self.message_id.lock().await;
                 *guard += 1;
-                let _ = respond_to.send(*guard);
-                drop(guard);
+                Ok(MapperResponse::Id(*guard))
             }
-            MapperMessage::Cleanup { respond_to } => {
-                drain_internal_buffer(self).await;
-                let _ = respond_to.send(String::from("Cleanup Finished"));
+            MapperRequest::Cleanup => {
+                drain_internal_buffer(self).await?;
+                Ok(MapperResponse::CleanedUp(String::from("Cleanup Finished")))
             }
-            MapperMessage::ProcessFileTest {
-                filename,
-                respond_to,
-            } => {
-                let mut file = File::open(&filename).unwrap();
-                let mut contents = String::new();
-                file.read_to_string(&mut contents).unwrap();
-                let _ = respond_to.send(contents);
+            MapperRequest::ProcessFileTest { filename } => std::fs::read_to_string(&filename)
+                .map(MapperResponse::Loaded)
+                .map_err(|e| Error::InputError(filename, e)),
+            MapperRequest::ProcessSingleFile { filename } => {
+                let (output, _) = self.map_split(&InputSplit::whole(filename))?;
+                Ok(MapperResponse::Mapped(
+                    output
+                        .iter()
+                        .map(|(key, values)| {
+                            (
+                                key.to_string(),
+                                values.iter().map(|value| value.to_string()).collect(),
+                            )
+                        })
+                        .collect(),
+                ))
             }
-            MapperMessage::ProcessSingleFile {
-                filename,
-                respond_to,
-            } => {
-                let file = File::open(&filename).unwrap();
-                let reader = BufReader::new(file);
-                let mut wordcount: BTreeMap<String, u32> = BTreeMap::new();
-
-                for line in reader.lines().filter_map(Result::ok) {
-                    for word in line.split_ascii_whitespace() {
-                        *wordcount
-                            .entry(String::from(word).to_lowercase())
-                            .or_insert(0) += 1;
-                    }
-                }
+            MapperRequest::ProcessFileWithBuffer { split } => {
+                // map before draining, so a split that fails to map never leaves
+                // behind a drain the scheduler didn't hear about
+                let (output, skipped) = self.map_split(&split)?;
+                self.internal_buffer.lock().await.push(output);
 
-                let _ = respond_to.send(wordcount);
-            }
-            MapperMessage::ProcessFileWithBuffer {
-                filename,
-                respond_to,
-            } => {
                 let mut guard = self.message_id.lock().await;
                 *guard += 1;
-                let message_id = guard.clone();
-                if *guard % 10 == 0 {
+                if *guard % self.job.flush_every == 0 {
                     drop(guard);
-                    drain_internal_buffer(self).await;
-                }
-                let file = File::open(&filename).unwrap();
-                let reader = BufReader::new(file);
-                let mut wordcount: BTreeMap<String, u32> = BTreeMap::new();
-
-                for line in reader.lines().filter_map(Result::ok) {
-                    for word in line.split_ascii_whitespace() {
-                        *wordcount
-                            .entry(String::from(word).to_lowercase())
-                            .or_insert(0) += 1;
-                    }
+                    drain_internal_buffer(self).await?;
                 }
+                let guard = self.internal_buffer.lock().await;
+                Ok(MapperResponse::Buffered(guard.len(), skipped))
+            }
+        }
+    }
+}
 
-                let mut guard = self.internal_buffer.lock().await;
-                guard.push(wordcount);
-                drop(guard);
+/// The message a panic was raised with, if it was raised with one.
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "no message"
+    }
+}
 
-                let _ = respond_to.send(message_id);
-            }
+/// Folds every buffered file's output into one map and runs the combiner over
+/// each key, so a key is written once per drain rather than once per file.
+fn combine_buffers<M: MapFn>(
+    buffers: impl Iterator<Item = MapOutput<M>>,
+    combiner: &dyn CombineFn<M::Key, M::Value>,
+) -> MapOutput<M> {
+    let mut merged: MapOutput<M> = BTreeMap::new();
+    for buffer in buffers {
+        for (key, mut values) in buffer {
+            merged.entry(key).or_default().append(&mut values);
         }
     }
+    for (key, values) in merged.iter_mut() {
+        *values = combiner.combine(key, std::mem::take(values));
+    }
+    merged
 }
 
-async fn drain_internal_buffer(mapper: &mut Mapper) {
+async fn drain_internal_buffer<M: MapFn>(mapper: &mut Mapper<M>) -> Result<()> {
     let mut guard = Mutex::lock(&mapper.internal_buffer).await;
-    println!("Lock aquired in drain buffer");
     let internal_buffer = guard.deref_mut();
-    for buffer in internal_buffer.iter() {
-        let mut target_partition = 5; //default partition
-        for (key, value) in buffer.iter() {
-            if let Some(first_letter) = key.chars().next() {
-                for (partition, letters) in helpers::PARTITION_MAP.iter() {
-                    if letters.contains(&first_letter) {
-                        target_partition = *partition;
-                        break;
-                    }
-                }
+    let buffers: Vec<MapOutput<M>> = match mapper.job.combiner {
+        Some(ref combiner) => vec![combine_buffers::<M>(internal_buffer.drain(..), &**combiner)],
+        None => std::mem::take(internal_buffer),
+    };
+    // group the records per partition so each drain is a single batch for the writer
+    let partitions = mapper.job.partitioner.partitions();
+    let format = mapper.job.record_format;
+    let mut records: BTreeMap<usize, Vec<Record>> = BTreeMap::new();
+    for buffer in buffers.iter() {
+        for (key, values) in buffer.iter() {
+            let target_partition = mapper.job.partitioner.partition(key);
+            if target_partition >= partitions {
+                return Err(Error::PartitionOutOfRange {
+                    partition: target_partition,
+                    partitions,
+                });
             }
-            let file_name = format!("./tmp/{}.txt", target_partition);
-            let content = format!("{}:{}\n", key, value);
-
-
-            let zero = mapper.writer_handle.begin_writing(PathBuf::from(&file_name)).await;
-            assert_eq!(zero.status, 200);
-
-
-            let message = Request {
-                header: writer::RequestHeader::Payload {
-                    key: PathBuf::from_str(&file_name).unwrap(),
-                },
-                body: Some(content),
-            };
-
-            let _ = mapper.writer_handle.write_message(message).await;
+            let key = key.to_string().into_bytes();
+            records
+                .entry(target_partition)
+                .or_default()
+                .extend(values.iter().map(|value| {
+                    let mut encoded = Vec::new();
+                    record::encode_value(format, value, &mut encoded);
+                    (key.clone(), encoded)
+                }));
+        }
+    }
 
+    // every drain gets its own file in each partition. a file only shows up once
+    // it is whole, but the files are committed one after the other, so a drain
+    // that fails while committing may leave some of its partitions behind.
+    let drain = NEXT_DRAIN.fetch_add(1, Ordering::Relaxed);
+    let file_names: Vec<PathBuf> = records
+        .keys()
+        .map(|partition| {
+            mapper
+                .job
+                .scratch_dir
+                .join(partition.to_string())
+                .join(format!("{}{}.part", mapper.job.drain_prefix, drain))
+        })
+        .collect();
+    let batches = file_names
+        .iter()
+        .cloned()
+        .zip(records.into_values())
+        .map(|(key, records)| Batch { key, records })
+        .collect();
+    let written = write_drain(&mut mapper.writer_handle, &file_names, batches).await;
+    if written.is_err() {
+        for file_name in file_names.iter() {
+            let _ = mapper.writer_handle.abort_writing(file_name.clone()).await;
         }
+        return written;
+    }
+    for file_name in file_names {
+        mapper.writer_handle.end_writing(file_name).await?;
     }
-    internal_buffer.clear();
     drop(guard);
+    Ok(())
+}
 
-    println!("Drained internal buffer");
+async fn write_drain(
+    writer_handle: &mut writer::WriterHandle,
+    file_names: &[PathBuf],
+    batches: Vec<Batch>,
+) -> Result<()> {
+    for file_name in file_names.iter() {
+        let zero = writer_handle.begin_writing(file_name.clone()).await?;
+        if zero.status != 200 {
+            return Err(Error::WriteRejected(file_name.clone()));
+        }
+    }
+    if !batches.is_empty() {
+        let written = writer_handle.write_batch(batches).await?;
+        if written.status != 200 {
+            return Err(Error::WriteRejected(file_names[0].clone()));
+        }
+    }
+    Ok(())
 }
 
-pub async fn run_mapper(mut mapper: Mapper) {
-    while let Some(msg) = mapper.receiver.recv().await {
-        mapper.handle_message(msg).await;
+pub async fn run_mapper<M: MapFn>(mut mapper: Mapper<M>) {
+    while let Some((request, respond_to)) = mapper.receiver.recv().await {
+        let _ = respond_to.send(mapper.handle_message(request).await);
     }
 }
 
-#[derive(Clone)]
-pub struct HandleMapper {
-    sender: mpsc::Sender<MapperMessage>,
+pub struct HandleMapper<M: MapFn> {
+    transport: Transport<MapperRequest, MapperResponse>,
+    map_fn: PhantomData<fn() -> M>,
 }
 
-impl HandleMapper {
-    pub fn new(writer: writer::WriterHandle) -> Self {
+// derive(Clone) would needlessly require M: Clone
+impl<M: MapFn> Clone for HandleMapper<M> {
+    fn clone(&self) -> Self {
+        Self {
+            transport: self.transport.clone(),
+            map_fn: PhantomData,
+        }
+    }
+}
+
+fn unexpected(response: MapperResponse) -> Error {
+    Error::Protocol(format!("unexpected mapper response {:?}", response))
+}
+
+impl<M: MapFn> HandleMapper<M> {
+    pub fn new(writer: writer::WriterHandle, job: MapJob<M>) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let mapper = Mapper::new(receiver, writer);
+        let mapper = Mapper::new(receiver, writer, job);
         tokio::spawn(run_mapper(mapper));
 
-        Self { sender }
+        Self::with_transport(Transport::local("mapper", sender))
+    }
+    /// A handle to a mapper reached through `transport`, e.g. one served on a socket.
+    pub fn with_transport(transport: Transport<MapperRequest, MapperResponse>) -> Self {
+        Self {
+            transport,
+            map_fn: PhantomData,
+        }
     }
-    pub async fn get_unique_id(&self) -> usize {
-        let (send, recv) = oneshot::channel();
-        let message = MapperMessage::GetId { respond_to: send };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Actor died")
+    /// Where the mapper behind this handle takes its calls, to serve it elsewhere.
+    pub fn transport(&self) -> Transport<MapperRequest, MapperResponse> {
+        self.transport.clone()
     }
-    pub async fn load_file(&self, filename: PathBuf) -> String {
-        let (send, recv) = oneshot::channel();
-        let message = MapperMessage::ProcessFileTest {
-            filename,
-            respond_to: send,
-        };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Actor ded")
-    }
-    pub async fn process_file(&self, filename: PathBuf) -> BTreeMap<String, u32> {
-        let (send, recv) = oneshot::channel();
-        let message = MapperMessage::ProcessSingleFile {
-            filename,
-            respond_to: send,
-        };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Actor ded")
-    }
-    pub async fn process_file_with_buffer(&self, filename: PathBuf) -> usize {
-        let (send, recv) = oneshot::channel();
-        let message = MapperMessage::ProcessFileWithBuffer {
-            filename,
-            respond_to: send,
+    pub async fn get_unique_id(&self) -> Result<usize> {
+        match self.transport.call(MapperRequest::GetId).await? {
+            MapperResponse::Id(id) => Ok(id),
+            other => Err(unexpected(other)),
+        }
+    }
+    pub async fn load_file(&self, filename: PathBuf) -> Result<String> {
+        match self
+            .transport
+            .call(MapperRequest::ProcessFileTest { filename })
+            .await?
+        {
+            MapperResponse::Loaded(contents) => Ok(contents),
+            other => Err(unexpected(other)),
+        }
+    }
+    pub async fn process_file(&self, filename: PathBuf) -> Result<MapOutput<M>> {
+        let pairs = match self
+            .transport
+            .call(MapperRequest::ProcessSingleFile { filename })
+            .await?
+        {
+            MapperResponse::Mapped(pairs) => pairs,
+            other => return Err(unexpected(other)),
         };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Actor ded")
+        fn parse<T: std::str::FromStr>(text: &str) -> Result<T> {
+            text.parse()
+                .map_err(|_| Error::Protocol(format!("cannot parse {:?} from a mapper", text)))
+        }
+        let mut output: MapOutput<M> = BTreeMap::new();
+        for (key, values) in pairs {
+            let values = values
+                .iter()
+                .map(|value| parse(value))
+                .collect::<Result<Vec<_>>>()?;
+            output.insert(parse(&key)?, values);
+        }
+        Ok(output)
+    }
+    /// Maps `split` into the mapper's buffer, draining the buffer first every
+    /// `flush_every` splits. Returns how many splits are now buffered, i.e. would
+    /// be lost if the mapper died before its next drain, and why each bad record
+    /// left out of `split` was bad.
+    pub async fn process_file_with_buffer(
+        &self,
+        split: InputSplit,
+    ) -> Result<(usize, Vec<String>)> {
+        match self
+            .transport
+            .call(MapperRequest::ProcessFileWithBuffer { split })
+            .await?
+        {
+            MapperResponse::Buffered(buffered, skipped) => Ok((buffered, skipped)),
+            other => Err(unexpected(other)),
+        }
     }
-    pub async fn cleanup_signal(&self) -> String {
-        let (send, recv) = oneshot::channel();
-        let message = MapperMessage::Cleanup { respond_to: send };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Cleanup actor ded")
+    pub async fn cleanup_signal(&self) -> Result<String> {
+        match self.transport.call(MapperRequest::Cleanup).await? {
+            MapperResponse::CleanedUp(message) => Ok(message),
+            other => Err(unexpected(other)),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::input::Lines;
+    use crate::job::WordCount;
+    use crate::partitioner::HashPartitioner;
 
     #[tokio::test]
     async fn test_mapper() {
         let writer_handle = writer::WriterHandle::new().await;
-        let mapper = HandleMapper::new(writer_handle);
-        let id1 = mapper.get_unique_id().await;
-        let id2 = mapper.get_unique_id().await;
+        let mapper = HandleMapper::new(
+            writer_handle,
+            MapJob::new(
+                Arc::new(WordCount),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
+        let id1 = mapper.get_unique_id().await.unwrap();
+        let id2 = mapper.get_unique_id().await.unwrap();
         println!("id1: {}, id2: {}", id1, id2);
         assert_eq!(id1 + 1, id2);
     }
@@ -239,29 +476,181 @@ mod tests {
     #[tokio::test]
     async fn test_mapper_load_file() {
         let writer_handle = writer::WriterHandle::new().await;
-        let mapper = HandleMapper::new(writer_handle);
-        let res = mapper.load_file(PathBuf::from("./test.txt")).await;
+        let mapper = HandleMapper::new(
+            writer_handle,
+            MapJob::new(
+                Arc::new(WordCount),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
+        let res = mapper.load_file(PathBuf::from("./test.txt")).await.unwrap();
         assert_eq!("Hello World!\n", &res);
     }
 
     #[tokio::test]
     async fn test_mapper_process_file() {
         let writer_handle = writer::WriterHandle::new().await;
-        let mapper = HandleMapper::new(writer_handle);
-        let res = mapper.process_file(PathBuf::from("./test.txt")).await;
+        let mapper = HandleMapper::new(
+            writer_handle,
+            MapJob::new(
+                Arc::new(WordCount),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
+        let res = mapper
+            .process_file(PathBuf::from("./test.txt"))
+            .await
+            .unwrap();
         let map: BTreeMap<String, u32> =
             BTreeMap::from([(String::from("hello"), 1), (String::from("world!"), 1)]);
         assert_eq!(&res.len(), &map.len());
         assert!(&res.keys().all(|key| map.contains_key(key)))
     }
 
+    struct LineLengths;
+
+    impl MapFn for LineLengths {
+        type Input = String;
+        type Key = usize;
+        type Value = String;
+
+        fn map(&self, line: &String, emit: &mut dyn FnMut(usize, String)) {
+            emit(line.len(), line.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mapper_custom_job() {
+        let writer_handle = writer::WriterHandle::new().await;
+        let mapper = HandleMapper::new(
+            writer_handle,
+            MapJob::new(
+                Arc::new(LineLengths),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
+        let res = mapper
+            .process_file(PathBuf::from("./test.txt"))
+            .await
+            .unwrap();
+        assert_eq!(
+            res,
+            BTreeMap::from([(12, vec![String::from("Hello World!")])])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mapper_over_stream() {
+        let mapper = HandleMapper::new(
+            writer::WriterHandle::new().await,
+            MapJob::new(
+                Arc::new(LineLengths),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(crate::transport::serve(server, mapper.transport()));
+        let remote: HandleMapper<LineLengths> =
+            HandleMapper::with_transport(Transport::connect("mapper", client));
+        assert_eq!(
+            remote
+                .process_file(PathBuf::from("./test.txt"))
+                .await
+                .unwrap(),
+            BTreeMap::from([(12, vec![String::from("Hello World!")])])
+        );
+        assert!(matches!(
+            remote.load_file(PathBuf::from("./missing.txt")).await,
+            Err(Error::InputError(..))
+        ));
+    }
+
+    #[test]
+    fn test_combine_buffers() {
+        let first = BTreeMap::from([
+            (String::from("cat"), vec![1, 1]),
+            (String::from("dog"), vec![1]),
+        ]);
+        let second = BTreeMap::from([(String::from("cat"), vec![1])]);
+        let combined = combine_buffers::<WordCount>(vec![first, second].into_iter(), &WordCount);
+        assert_eq!(
+            combined,
+            BTreeMap::from([
+                (String::from("cat"), vec![3]),
+                (String::from("dog"), vec![1])
+            ])
+        );
+    }
+
     #[tokio::test]
     async fn test_mapper_process_files() {
         let writer_handle = writer::WriterHandle::new().await;
-        let mapper = HandleMapper::new(writer_handle);
+        let mapper = HandleMapper::new(
+            writer_handle,
+            MapJob::new(
+                Arc::new(WordCount),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
+        let res = mapper
+            .process_file_with_buffer(InputSplit::whole(PathBuf::from("./test.txt")))
+            .await
+            .unwrap();
+        assert_eq!(res, (1, Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_mapper_maps_only_its_split() {
+        let (_, receiver) = mpsc::channel(1);
+        let mapper = Mapper::new(
+            receiver,
+            writer::WriterHandle::new().await,
+            MapJob::new(
+                Arc::new(LineLengths),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
+        let path = std::env::temp_dir().join(format!("split-map-{}.txt", std::process::id()));
+        std::fs::write(&path, "first\nsecond\nthird\n").unwrap();
+        // just the middle line
+        let split = InputSplit {
+            path: path.clone(),
+            offset: 6,
+            len: 7,
+        };
+        assert_eq!(
+            mapper.map_split(&split).unwrap(),
+            (
+                BTreeMap::from([(6, vec![String::from("second")])]),
+                Vec::new()
+            )
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_mapper_missing_file() {
+        let writer_handle = writer::WriterHandle::new().await;
+        let mapper = HandleMapper::new(
+            writer_handle,
+            MapJob::new(
+                Arc::new(WordCount),
+                Arc::new(Lines::default()),
+                Arc::new(HashPartitioner::new(1)),
+            ),
+        );
         let res = mapper
-            .process_file_with_buffer(PathBuf::from("./test.txt"))
+            .process_file_with_buffer(InputSplit::whole(PathBuf::from("./no-such-file.txt")))
             .await;
-        assert_eq!(res, 1);
+        assert!(matches!(res, Err(Error::InputError(..))));
+        // the actor survives a bad file and keeps serving requests, and the
+        // file it couldn't map doesn't count towards its next drain
+        assert_eq!(mapper.get_unique_id().await.unwrap(), 1);
     }
 }
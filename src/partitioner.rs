@@ -0,0 +1,201 @@
+use std::fmt::{Display, Write};
+use std::hash::Hasher;
+use std::path::PathBuf;
+
+use crate::input::{InputFormat, InputSplit};
+use crate::job::MapFn;
+
+/// Decides which reducer partition an intermediate key is written to. Every
+/// mapper of a job shares one partitioner, so a key always lands in the same
+/// partition no matter which mapper emitted it.
+pub trait Partitioner<K>: Send + Sync + 'static {
+    /// Number of partitions, which is the number of reducers.
+    fn partitions(&self) -> usize;
+
+    /// The partition of `key`, in `0..self.partitions()`.
+    fn partition(&self, key: &K) -> usize;
+
+    /// The keys the partitions are split at, if they hold ranges of keys, for
+    /// setting the same partitioner up somewhere else.
+    fn boundaries(&self) -> Option<&[K]> {
+        None
+    }
+}
+
+/// Spreads keys evenly over the reducers by hashing them. The hash is taken
+/// over a key's `Display` text, which is how it is written to the partitions,
+/// so workers built for different platforms still agree on it.
+#[derive(Debug, Clone, Copy)]
+pub struct HashPartitioner {
+    partitions: usize,
+}
+
+impl HashPartitioner {
+    pub fn new(reducers: usize) -> Self {
+        assert!(reducers > 0, "a job needs at least one reducer");
+        Self {
+            partitions: reducers,
+        }
+    }
+}
+
+impl<K: Display> Partitioner<K> for HashPartitioner {
+    fn partitions(&self) -> usize {
+        self.partitions
+    }
+
+    fn partition(&self, key: &K) -> usize {
+        let mut hasher = Fnv1a::default();
+        // writing to the hasher can't fail
+        let _ = write!(hasher, "{}", key);
+        (hasher.finish() % self.partitions as u64) as usize
+    }
+}
+
+/// FNV-1a. Unlike `DefaultHasher`, which is randomly seeded, it gives the same
+/// hash for the same bytes in every mapper and every run.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+impl Write for Fnv1a {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        Hasher::write(self, s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Gives each reducer a contiguous range of keys, with the range boundaries
+/// picked from a sample of the keys so that skewed key spaces still split
+/// into partitions of similar size.
+#[derive(Debug, Clone)]
+pub struct RangePartitioner<K> {
+    /// partition `i` holds the keys below `boundaries[i]` and not below `boundaries[i - 1]`
+    boundaries: Vec<K>,
+    partitions: usize,
+}
+
+impl<K: Ord + Clone> RangePartitioner<K> {
+    pub fn from_samples(mut samples: Vec<K>, reducers: usize) -> Self {
+        assert!(reducers > 0, "a job needs at least one reducer");
+        samples.sort();
+        let mut boundaries: Vec<K> = (1..reducers)
+            .filter_map(|i| samples.get(i * samples.len() / reducers).cloned())
+            .collect();
+        boundaries.dedup();
+        Self::from_boundaries(boundaries, reducers)
+    }
+
+    /// A partitioner splitting at `boundaries`, those another one picked.
+    pub fn from_boundaries(boundaries: Vec<K>, reducers: usize) -> Self {
+        assert!(reducers > 0, "a job needs at least one reducer");
+        Self {
+            boundaries,
+            partitions: reducers,
+        }
+    }
+}
+
+impl<K: Ord + Send + Sync + 'static> Partitioner<K> for RangePartitioner<K> {
+    fn partitions(&self) -> usize {
+        self.partitions
+    }
+
+    fn partition(&self, key: &K) -> usize {
+        self.boundaries.partition_point(|boundary| boundary <= key)
+    }
+
+    fn boundaries(&self) -> Option<&[K]> {
+        Some(&self.boundaries)
+    }
+}
+
+/// Runs the map function over the records in the first `bytes_per_file` bytes
+/// of each file and returns every key it emits, as a sample for
+/// `RangePartitioner`. A record cut off at the end of a sample, or that can't
+/// be read, just ends that file's sample.
+pub fn sample_keys<M: MapFn>(
+    map_fn: &M,
+    input_format: &dyn InputFormat<Record = M::Input>,
+    files: &[PathBuf],
+    bytes_per_file: u64,
+) -> Vec<M::Key> {
+    let mut samples = Vec::new();
+    for file in files {
+        let split = InputSplit {
+            path: file.clone(),
+            offset: 0,
+            len: bytes_per_file,
+        };
+        let _ = input_format.read(&split, &mut |record| {
+            map_fn.map(&record, &mut |key, _| samples.push(key));
+        });
+    }
+    samples
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_partitioner() {
+        let partitioner = HashPartitioner::new(4);
+        for word in ["apple", "Zebra", "42", "über", ":", ""] {
+            let key = String::from(word);
+            let partition = partitioner.partition(&key);
+            assert!(partition < 4);
+            assert_eq!(partition, partitioner.partition(&key));
+        }
+        // a key's text decides, not how wide its type is on this platform
+        for number in [0u64, 7, 1 << 40] {
+            let text = number.to_string();
+            assert_eq!(partitioner.partition(&number), partitioner.partition(&text));
+            if let Ok(narrow) = u32::try_from(number) {
+                assert_eq!(partitioner.partition(&narrow), partitioner.partition(&text));
+            }
+        }
+        // pinned, so a change to the hash that would split keys between builds shows up
+        assert_eq!(Fnv1a::default().finish(), 0xcbf29ce484222325);
+        let mut hasher = Fnv1a::default();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn test_range_partitioner() {
+        let samples: Vec<String> = ["a", "b", "c", "d", "e", "f", "g", "h"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let partitioner = RangePartitioner::from_samples(samples, 4);
+        assert_eq!(Partitioner::<String>::partitions(&partitioner), 4);
+        assert_eq!(partitioner.partition(&String::from("a")), 0);
+        assert_eq!(partitioner.partition(&String::from("bz")), 0);
+        assert_eq!(partitioner.partition(&String::from("c")), 1);
+        assert_eq!(partitioner.partition(&String::from("f")), 2);
+        assert_eq!(partitioner.partition(&String::from("zzz")), 3);
+
+        let boundaries = partitioner.boundaries().unwrap().to_vec();
+        assert_eq!(boundaries, vec!["c", "e", "g"]);
+        let copy = RangePartitioner::from_boundaries(boundaries, 4);
+        assert_eq!(copy.partition(&String::from("f")), 2);
+    }
+}
@@ -0,0 +1,411 @@
+use std::any::TypeId;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::process::{Child, Command};
+
+use crate::config::JobConfig;
+use crate::mapper::{HandleMapper, MapJob, SharedPartitioner};
+use crate::partitioner::{HashPartitioner, RangePartitioner};
+use crate::reducer::HandleReducer;
+use crate::transport::{self, Transport};
+use crate::writer::WriterHandle;
+use crate::{job, Error, Result};
+
+/// What a child process is told to be, in the first frame it reads.
+#[derive(Debug, Serialize, Deserialize)]
+enum Setup {
+    /// A mapper of the job, whose drains go to the parent's writer over a
+    /// second connection and are named with `drain_prefix`. A job partitioned
+    /// by range comes with the `boundaries` the parent sampled, so that no
+    /// child reads the input to sample them again.
+    Mapper {
+        config: JobConfig,
+        boundaries: Option<Vec<String>>,
+        scratch_dir: PathBuf,
+        drain_prefix: String,
+    },
+    Reducer {
+        config: JobConfig,
+        scratch_dir: PathBuf,
+    },
+}
+
+/// Fails unless `F`, a map or reduce function, is the word count job's. The
+/// children only know how to run that job, so no other can have its mappers
+/// and reducers moved into child processes.
+pub fn check_job<F: 'static>() -> Result<()> {
+    if TypeId::of::<F>() == TypeId::of::<job::WordCount>() {
+        return Ok(());
+    }
+    Err(Error::InvalidArguments(vec![format!(
+        "--processes only runs the word count job, not {}",
+        std::any::type_name::<F>()
+    )]))
+}
+
+/// Starts a mapper in a child process of its own. It runs the word count job
+/// as `config` sets it up, partitioned like `map_job`, and drains into its
+/// scratch directory through `writer`, the parent's writer.
+/// If the child dies, calls on the handle fail with `Error::ActorGone("mapper")`
+/// as they would for a mapper in this process. Returns the handle along with
+/// what the names of the mapper's drains start with.
+pub async fn spawn_mapper<M: job::MapFn>(
+    config: &JobConfig,
+    map_job: &MapJob<M>,
+    writer: &WriterHandle,
+) -> Result<(HandleMapper<M>, String)> {
+    check_job::<M>()?;
+    // keys go over as text, the way they are written to the partitions
+    let boundaries = map_job
+        .partitioner
+        .boundaries()
+        .map(|keys| keys.iter().map(ToString::to_string).collect::<Vec<_>>());
+    retry_start("mapper", config, || {
+        start_mapper(config, &boundaries, &map_job.scratch_dir, writer)
+    })
+    .await
+}
+
+async fn start_mapper<M: job::MapFn>(
+    config: &JobConfig,
+    boundaries: &Option<Vec<String>>,
+    scratch_dir: &Path,
+    writer: &WriterHandle,
+) -> Result<(HandleMapper<M>, String)> {
+    let (id, mut child, listener, socket) = spawn_child("mapper", scratch_dir)?;
+    let result = async {
+        let mut stream = accept(&listener, &mut child, config.heartbeat_timeout).await?;
+        // drains are only numbered apart within a process
+        let drain_prefix = format!("p{}-", id);
+        let setup = Setup::Mapper {
+            config: config.clone(),
+            boundaries: boundaries.clone(),
+            scratch_dir: scratch_dir.to_path_buf(),
+            drain_prefix: drain_prefix.clone(),
+        };
+        transport::write_frame(&mut stream, 0, &setup).await?;
+        let writes = accept(&listener, &mut child, config.heartbeat_timeout).await?;
+        tokio::spawn(transport::serve(writes, writer.transport()));
+        let mapper = HandleMapper::with_transport(Transport::connect("mapper", stream));
+        Ok((mapper, drain_prefix))
+    }
+    .await;
+    let _ = std::fs::remove_file(&socket);
+    if result.is_err() {
+        let _ = child.start_kill();
+    }
+    reap("mapper", id, child);
+    result
+}
+
+/// Starts a reducer for the word count job in a child process of its own.
+pub async fn spawn_reducer(config: &JobConfig, scratch_dir: &Path) -> Result<HandleReducer> {
+    retry_start("reducer", config, || start_reducer(config, scratch_dir)).await
+}
+
+async fn start_reducer(config: &JobConfig, scratch_dir: &Path) -> Result<HandleReducer> {
+    let (id, mut child, listener, socket) = spawn_child("reducer", scratch_dir)?;
+    let result = async {
+        let mut stream = accept(&listener, &mut child, config.heartbeat_timeout).await?;
+        let setup = Setup::Reducer {
+            config: config.clone(),
+            scratch_dir: scratch_dir.to_path_buf(),
+        };
+        transport::write_frame(&mut stream, 0, &setup).await?;
+        Ok(HandleReducer::with_transport(Transport::connect(
+            "reducer", stream,
+        )))
+    }
+    .await;
+    let _ = std::fs::remove_file(&socket);
+    if result.is_err() {
+        let _ = child.start_kill();
+    }
+    reap("reducer", id, child);
+    result
+}
+
+/// Starts a child with `start`, starting another up to `max_retries` times if
+/// it dies or hangs before it is set up, like one that dies later would be replaced.
+async fn retry_start<T, S, F>(kind: &str, config: &JobConfig, mut start: S) -> Result<T>
+where
+    S: FnMut() -> F,
+    F: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match start().await {
+            Ok(started) => return Ok(started),
+            Err(e) if attempt < config.max_retries => {
+                attempt += 1;
+                eprintln!(
+                    "Could not start a {} process (attempt {}): {}, retrying",
+                    kind, attempt, e
+                );
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Runs this program again as `tinymapreduce child <SOCKET>`, with a socket
+/// in `scratch_dir` for it to connect to.
+fn spawn_child(kind: &str, scratch_dir: &Path) -> Result<(usize, Child, UnixListener, PathBuf)> {
+    static NEXT_CHILD: AtomicUsize = AtomicUsize::new(0);
+    let id = NEXT_CHILD.fetch_add(1, Ordering::Relaxed);
+    let socket = scratch_dir.join(format!("{}-{}.sock", kind, id));
+    let listener = UnixListener::bind(&socket)?;
+    let child = Command::new(std::env::current_exe()?)
+        .arg("child")
+        .arg(&socket)
+        .stdin(Stdio::null())
+        // a child that never got handed to `reap` is not left behind
+        .kill_on_drop(true)
+        .spawn()?;
+    Ok((id, child, listener, socket))
+}
+
+/// Waits for `child` to connect, unless it exits first or takes longer than
+/// `timeout`, e.g. hung before it got that far.
+async fn accept(
+    listener: &UnixListener,
+    child: &mut Child,
+    timeout: Duration,
+) -> Result<UnixStream> {
+    let accepted = tokio::time::timeout(timeout, async {
+        tokio::select! {
+            accepted = listener.accept() => Ok(accepted?.0),
+            status = child.wait() => Err(Error::Protocol(format!(
+                "child process exited before it connected ({})",
+                status?
+            ))),
+        }
+    })
+    .await;
+    accepted.unwrap_or_else(|_| {
+        Err(Error::Protocol(format!(
+            "child process did not connect within {:?}",
+            timeout
+        )))
+    })
+}
+
+/// Reports a child that exits with an error once it does, e.g. after a panic.
+fn reap(kind: &'static str, id: usize, mut child: Child) {
+    tokio::spawn(async move {
+        match child.wait().await {
+            Ok(status) if !status.success() => {
+                eprintln!("The {} in process {} died ({})", kind, id, status)
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Could not wait for the {} in process {}: {}", kind, id, e),
+        }
+    });
+}
+
+/// `tinymapreduce child <SOCKET>`: connects to the parent that started it and
+/// serves the actor the parent sets it up as, until the parent hangs up.
+pub async fn run_child(args: &[String]) -> Result<()> {
+    let [socket] = args else {
+        return Err(Error::InvalidArguments(args.to_vec()));
+    };
+    let mut stream = UnixStream::connect(socket).await?;
+    let Some((_, setup)) = transport::read_frame(&mut stream).await? else {
+        return Ok(());
+    };
+    match setup {
+        Setup::Mapper {
+            config,
+            boundaries,
+            scratch_dir,
+            drain_prefix,
+        } => {
+            let writes = UnixStream::connect(socket).await?;
+            let writer = WriterHandle::with_transport(Transport::connect("writer", writes));
+            let partitioner: SharedPartitioner<job::WordCount> = match boundaries {
+                Some(boundaries) => Arc::new(RangePartitioner::from_boundaries(
+                    boundaries,
+                    config.reducers,
+                )),
+                None => Arc::new(HashPartitioner::new(config.reducers)),
+            };
+            let mut map_job = crate::word_count_job_with(&config, partitioner);
+            map_job.scratch_dir = scratch_dir;
+            map_job.drain_prefix = drain_prefix;
+            let mapper = HandleMapper::new(writer, map_job);
+            transport::serve(stream, mapper.transport()).await
+        }
+        Setup::Reducer {
+            config,
+            scratch_dir,
+        } => {
+            let (output_format, _) = crate::reduce_output(&config, &scratch_dir);
+            let reducer = HandleReducer::with_output_format(
+                Arc::new(job::WordCount),
+                config.record_format,
+                config.compression,
+                output_format,
+            );
+            transport::serve(stream, reducer.transport()).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::{self, RecordFormat, RecordWriter};
+
+    #[tokio::test]
+    async fn test_child_reducer() {
+        let dir = std::env::temp_dir().join(format!("child-reducer-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let partition = dir.join("scratch").join("0");
+        std::fs::create_dir_all(&partition).unwrap();
+        let file = std::fs::File::create(partition.join("p0-0.part")).unwrap();
+        let mut writer = RecordWriter::new(file, RecordFormat::Binary);
+        for (key, value) in [("one", 1u32), ("two", 2), ("one", 3)] {
+            let mut encoded = Vec::new();
+            record::encode_value(RecordFormat::Binary, &value, &mut encoded);
+            writer.write(key.as_bytes(), &encoded).unwrap();
+        }
+        writer.finish().unwrap();
+        let args: Vec<String> = [
+            dir.join("input").to_str().unwrap(),
+            "-o",
+            dir.join("output").to_str().unwrap(),
+            "--no-merge",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let config = JobConfig::from_args(&args).unwrap();
+
+        // the child's side runs in a task here rather than in a process of its own
+        let socket = dir.join("reducer-test.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let args = vec![socket.to_string_lossy().into_owned()];
+        let child = tokio::spawn(async move { run_child(&args).await });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let setup = Setup::Reducer {
+            config,
+            scratch_dir: dir.join("scratch"),
+        };
+        transport::write_frame(&mut stream, 0, &setup)
+            .await
+            .unwrap();
+        let reducer = HandleReducer::with_transport(Transport::connect("reducer", stream));
+
+        let sorted = reducer
+            .clone()
+            .shuffle(partition.to_string_lossy().into_owned())
+            .await
+            .unwrap();
+        let output = dir.join("part-0.tsv");
+        let summary = reducer
+            .reduce(sorted.to_string_lossy().into_owned(), output.clone())
+            .await
+            .unwrap();
+        assert_eq!(summary.records, 3);
+        assert_eq!(std::fs::read_to_string(output).unwrap(), "one\t4\ntwo\t2\n");
+
+        // the child is done once the parent dropped its last handle
+        child.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_child_mapper_uses_parent_boundaries() {
+        let dir = std::env::temp_dir().join(format!("child-mapper-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("scratch")).unwrap();
+        let input = dir.join("a.txt");
+        std::fs::write(&input, "apple zebra mango kiwi\n").unwrap();
+        // the child is never told where the input is, so it can't sample it
+        let args: Vec<String> = [dir.join("missing").to_str().unwrap(), "-r", "2"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let config = JobConfig::from_args(&args).unwrap();
+
+        let socket = dir.join("mapper-test.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let args = vec![socket.to_string_lossy().into_owned()];
+        let child = tokio::spawn(async move { run_child(&args).await });
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let setup = Setup::Mapper {
+            config: config.clone(),
+            boundaries: Some(vec![String::from("l")]),
+            scratch_dir: dir.join("scratch"),
+            drain_prefix: String::from("p0-"),
+        };
+        transport::write_frame(&mut stream, 0, &setup)
+            .await
+            .unwrap();
+        let (writes, _) = listener.accept().await.unwrap();
+        let writer = WriterHandle::with_format(config.record_format, config.compression).await;
+        tokio::spawn(transport::serve(writes, writer.transport()));
+        let mapper: HandleMapper<job::WordCount> =
+            HandleMapper::with_transport(Transport::connect("mapper", stream));
+
+        let split = crate::input::InputSplit {
+            path: input,
+            offset: 0,
+            len: u64::MAX,
+        };
+        mapper.process_file_with_buffer(split).await.unwrap();
+        mapper.cleanup_signal().await.unwrap();
+        let keys = |partition: &str| {
+            let mut keys = Vec::new();
+            for entry in std::fs::read_dir(dir.join("scratch").join(partition)).unwrap() {
+                let file = std::fs::File::open(entry.unwrap().path()).unwrap();
+                let reader =
+                    record::RecordReader::new(std::io::BufReader::new(file), config.record_format)
+                        .with_compression(config.compression);
+                for record in reader {
+                    keys.push(String::from_utf8(record.unwrap().0).unwrap());
+                }
+            }
+            keys.sort();
+            keys
+        };
+        assert_eq!(keys("0"), vec!["apple", "kiwi"]);
+        assert_eq!(keys("1"), vec!["mango", "zebra"]);
+
+        drop(mapper);
+        child.await.unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_accept_gives_up_on_hung_child() {
+        let dir = std::env::temp_dir().join(format!("child-hung-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let listener = UnixListener::bind(dir.join("hung.sock")).unwrap();
+        // alive, but never connects
+        let mut child = Command::new("sleep")
+            .arg("10")
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let accepted = accept(&listener, &mut child, Duration::from_millis(50)).await;
+        assert!(matches!(accepted, Err(Error::Protocol(message)) if message.contains("connect")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_child_needs_a_socket() {
+        assert!(matches!(
+            run_child(&[]).await,
+            Err(Error::InvalidArguments(_))
+        ));
+    }
+}
@@ -0,0 +1,568 @@
+use std::fmt::Write as _;
+use std::io::{self, prelude::*};
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::job::Value;
+
+/// Bytes of records collected before they are written out as one block.
+pub const BLOCK_BYTES: usize = 64 * 1024;
+
+/// Largest block a reader takes, stored or decompressed, so that a corrupt
+/// length can't make it allocate any amount of memory. A block is
+/// `BLOCK_BYTES` of records plus the one that filled it, so only a record of
+/// nearly this size makes a bigger one.
+pub const MAX_BLOCK_BYTES: usize = 64 * 1024 * 1024;
+
+/// An intermediate record: the key's text form and the encoded value.
+pub type Record = (Vec<u8>, Vec<u8>);
+
+/// How intermediate records are laid out in the partition files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecordFormat {
+    /// Blocks of varint length-prefixed records with typed values. Every block
+    /// starts with its length and a crc32 of its contents.
+    Binary,
+    /// One `key:value` line per record, for reading the partitions by eye.
+    /// A `\`, `:` or newline in a key or value is escaped with a `\`, the
+    /// newline as `\n`.
+    Text,
+}
+
+impl FromStr for RecordFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "binary" => Ok(RecordFormat::Binary),
+            "text" => Ok(RecordFormat::Text),
+            _ => Err(format!(
+                "unknown record format {:?}, expected binary or text",
+                s
+            )),
+        }
+    }
+}
+
+/// How the blocks of the binary record format are compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Lz4,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(format!("unknown compression {:?}, expected none or lz4", s)),
+        }
+    }
+}
+
+/// The compact binary form of an intermediate value.
+pub trait Encode: Sized {
+    fn encode(&self, out: &mut Vec<u8>);
+
+    /// Decodes a value from exactly the bytes `encode` wrote.
+    fn decode(bytes: &[u8]) -> Option<Self>;
+}
+
+macro_rules! encode_unsigned {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            fn encode(&self, out: &mut Vec<u8>) {
+                write_varint(*self as u64, out);
+            }
+
+            fn decode(mut bytes: &[u8]) -> Option<Self> {
+                let value = read_varint(&mut bytes)?;
+                if !bytes.is_empty() {
+                    return None;
+                }
+                value.try_into().ok()
+            }
+        }
+    )*};
+}
+
+macro_rules! encode_signed {
+    ($($t:ty),*) => {$(
+        impl Encode for $t {
+            // zigzag, so small negative numbers stay short too
+            fn encode(&self, out: &mut Vec<u8>) {
+                let value = *self as i64;
+                write_varint(((value << 1) ^ (value >> 63)) as u64, out);
+            }
+
+            fn decode(mut bytes: &[u8]) -> Option<Self> {
+                let value = read_varint(&mut bytes)?;
+                if !bytes.is_empty() {
+                    return None;
+                }
+                (((value >> 1) as i64) ^ -((value & 1) as i64)).try_into().ok()
+            }
+        }
+    )*};
+}
+
+encode_unsigned!(u8, u16, u32, u64, usize);
+encode_signed!(i8, i16, i32, i64, isize);
+
+impl Encode for f64 {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        Some(f64::from_le_bytes(bytes.try_into().ok()?))
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+pub fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads a varint off the front of `bytes`, or `None` if it is cut short.
+pub fn read_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+pub fn encode_value<V: Value>(format: RecordFormat, value: &V, out: &mut Vec<u8>) {
+    match format {
+        RecordFormat::Binary => value.encode(out),
+        RecordFormat::Text => {
+            let mut text = String::new();
+            let _ = write!(text, "{}", value);
+            out.extend_from_slice(text.as_bytes());
+        }
+    }
+}
+
+pub fn decode_value<V: Value>(format: RecordFormat, bytes: &[u8]) -> Option<V> {
+    match format {
+        RecordFormat::Binary => V::decode(bytes),
+        RecordFormat::Text => std::str::from_utf8(bytes).ok()?.parse().ok(),
+    }
+}
+
+/// Collects records and frames them into blocks.
+#[derive(Debug)]
+pub struct Blocks {
+    format: RecordFormat,
+    compression: Compression,
+    payload: Vec<u8>,
+}
+
+impl Blocks {
+    pub fn new(format: RecordFormat) -> Self {
+        Self {
+            format,
+            compression: Compression::None,
+            payload: Vec::new(),
+        }
+    }
+
+    /// Compresses every block. Only the binary format is ever compressed.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    pub fn push(&mut self, key: &[u8], value: &[u8]) {
+        match self.format {
+            RecordFormat::Binary => {
+                write_varint(key.len() as u64, &mut self.payload);
+                self.payload.extend_from_slice(key);
+                write_varint(value.len() as u64, &mut self.payload);
+                self.payload.extend_from_slice(value);
+            }
+            RecordFormat::Text => {
+                escape_text(key, &mut self.payload);
+                self.payload.push(b':');
+                escape_text(value, &mut self.payload);
+                self.payload.push(b'\n');
+            }
+        }
+    }
+
+    /// Whether enough records were pushed to write a block.
+    pub fn is_full(&self) -> bool {
+        self.payload.len() >= BLOCK_BYTES
+    }
+
+    /// Bytes of records pushed since the last block, before any compression.
+    pub fn pending_bytes(&self) -> usize {
+        self.payload.len()
+    }
+
+    /// Takes the records pushed so far as one block, ready to be written.
+    /// Takes nothing if no records were pushed.
+    pub fn take(&mut self) -> Vec<u8> {
+        if self.payload.is_empty() {
+            return Vec::new();
+        }
+        match self.format {
+            RecordFormat::Binary => {
+                if self.compression == Compression::Lz4 {
+                    self.payload = lz4_flex::compress_prepend_size(&self.payload);
+                }
+                // the checksum covers the stored bytes, so corruption is caught
+                // before anything is decompressed
+                let mut block = Vec::with_capacity(8 + self.payload.len());
+                block.extend_from_slice(&(self.payload.len() as u32).to_le_bytes());
+                block.extend_from_slice(&crc32fast::hash(&self.payload).to_le_bytes());
+                block.append(&mut self.payload);
+                block
+            }
+            RecordFormat::Text => std::mem::take(&mut self.payload),
+        }
+    }
+}
+
+/// Writes records to a file in blocks.
+pub struct RecordWriter<W: Write> {
+    inner: W,
+    blocks: Blocks,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(inner: W, format: RecordFormat) -> Self {
+        Self {
+            inner,
+            blocks: Blocks::new(format),
+        }
+    }
+
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.blocks = self.blocks.with_compression(compression);
+        self
+    }
+
+    pub fn write(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.blocks.push(key, value);
+        if self.blocks.is_full() {
+            self.inner.write_all(&self.blocks.take())?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the last block and flushes.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&self.blocks.take())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Reads back the records a `RecordWriter` or the writer actor wrote,
+/// checking every block against its checksum.
+pub struct RecordReader<R: BufRead> {
+    inner: R,
+    format: RecordFormat,
+    compression: Compression,
+    block: Vec<u8>,
+    position: usize,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    pub fn new(inner: R, format: RecordFormat) -> Self {
+        Self {
+            inner,
+            format,
+            compression: Compression::None,
+            block: Vec::new(),
+            position: 0,
+        }
+    }
+
+    /// Reads blocks written with `compression`.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    fn next_text(&mut self) -> io::Result<Option<Record>> {
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if self.inner.read_until(b'\n', &mut line)? == 0 {
+                return Ok(None);
+            }
+            if line.last() == Some(&b'\n') {
+                line.pop();
+            }
+            if !line.is_empty() {
+                break;
+            }
+        }
+        // the key ends at the only `:` that is not escaped
+        let mut fields = [Vec::new(), Vec::new()];
+        let mut field = 0;
+        let mut bytes = line.into_iter();
+        while let Some(byte) = bytes.next() {
+            match byte {
+                b'\\' => fields[field].push(match bytes.next() {
+                    Some(b'n') => b'\n',
+                    Some(escaped @ (b'\\' | b':')) => escaped,
+                    _ => return Err(invalid_data("bad escape in record")),
+                }),
+                b':' if field == 0 => field = 1,
+                b':' => return Err(invalid_data("unescaped `:` in record value")),
+                byte => fields[field].push(byte),
+            }
+        }
+        if field == 0 {
+            return Err(invalid_data("record without a value"));
+        }
+        let [key, value] = fields;
+        Ok(Some((key, value)))
+    }
+
+    fn next_binary(&mut self) -> io::Result<Option<Record>> {
+        if self.position == self.block.len() && !self.read_block()? {
+            return Ok(None);
+        }
+        let mut rest = &self.block[self.position..];
+        let key = read_field(&mut rest)?;
+        let value = read_field(&mut rest)?;
+        self.position = self.block.len() - rest.len();
+        Ok(Some((key, value)))
+    }
+
+    // false at a clean end of the file
+    fn read_block(&mut self) -> io::Result<bool> {
+        let mut header = [0u8; 8];
+        if self.inner.fill_buf()?.is_empty() {
+            return Ok(false);
+        }
+        self.inner.read_exact(&mut header)?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if len > MAX_BLOCK_BYTES {
+            return Err(too_big(len));
+        }
+        self.block.resize(len, 0);
+        self.inner.read_exact(&mut self.block)?;
+        if crc32fast::hash(&self.block) != checksum {
+            return Err(invalid_data("block checksum mismatch"));
+        }
+        if self.compression == Compression::Lz4 {
+            // the size the block decompresses to, which is allocated up front
+            let size = self
+                .block
+                .get(..4)
+                .map(|size| u32::from_le_bytes(size.try_into().unwrap()) as usize)
+                .ok_or_else(|| invalid_data("corrupt lz4 block: no size"))?;
+            if size > MAX_BLOCK_BYTES {
+                return Err(too_big(size));
+            }
+            self.block = lz4_flex::decompress_size_prepended(&self.block)
+                .map_err(|e| invalid_data(&format!("corrupt lz4 block: {}", e)))?;
+        }
+        self.position = 0;
+        Ok(true)
+    }
+}
+
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = io::Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.format {
+            RecordFormat::Binary => self.next_binary(),
+            RecordFormat::Text => self.next_text(),
+        };
+        record.transpose()
+    }
+}
+
+/// Writes `bytes` as the text format stores a key or value.
+fn escape_text(bytes: &[u8], out: &mut Vec<u8>) {
+    for &byte in bytes {
+        match byte {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b':' => out.extend_from_slice(b"\\:"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            byte => out.push(byte),
+        }
+    }
+}
+
+fn read_field(bytes: &mut &[u8]) -> io::Result<Vec<u8>> {
+    let len = read_varint(bytes).ok_or_else(|| invalid_data("truncated record"))? as usize;
+    if bytes.len() < len {
+        return Err(invalid_data("truncated record"));
+    }
+    let (field, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Ok(field.to_vec())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn too_big(len: usize) -> io::Error {
+    invalid_data(&format!(
+        "block of {} bytes is larger than the {} allowed",
+        len, MAX_BLOCK_BYTES
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(format: RecordFormat, records: &[Record]) -> Vec<Record> {
+        round_trip_compressed(format, Compression::None, records).0
+    }
+
+    fn round_trip_compressed(
+        format: RecordFormat,
+        compression: Compression,
+        records: &[Record],
+    ) -> (Vec<Record>, usize) {
+        let mut writer = RecordWriter::new(Vec::new(), format).with_compression(compression);
+        for (key, value) in records.iter() {
+            writer.write(key, value).unwrap();
+        }
+        let bytes = writer.finish().unwrap();
+        let read = RecordReader::new(&bytes[..], format)
+            .with_compression(compression)
+            .collect::<io::Result<_>>()
+            .unwrap();
+        (read, bytes.len())
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let mut value = Vec::new();
+        encode_value(RecordFormat::Binary, &300u32, &mut value);
+        assert_eq!(value, vec![0xac, 0x02]);
+        // keys may hold anything in the binary format
+        let records = vec![
+            (b"a:b\nc".to_vec(), value),
+            (Vec::new(), Vec::new()),
+            (vec![b'x'; BLOCK_BYTES], b"big".to_vec()),
+        ];
+        assert_eq!(round_trip(RecordFormat::Binary, &records), records);
+        assert_eq!(
+            decode_value::<u32>(RecordFormat::Binary, &records[0].1),
+            Some(300)
+        );
+        let mut negative = Vec::new();
+        encode_value(RecordFormat::Binary, &-3i64, &mut negative);
+        assert_eq!(negative, vec![5]);
+        assert_eq!(decode_value(RecordFormat::Binary, &negative), Some(-3i64));
+    }
+
+    #[test]
+    fn test_text_round_trip() {
+        let records = vec![
+            (b"a:b".to_vec(), b"1".to_vec()),
+            (b"hello".to_vec(), b"2".to_vec()),
+            (b"multi\nline\\".to_vec(), b"x:y\nz".to_vec()),
+            (Vec::new(), Vec::new()),
+        ];
+        let mut writer = RecordWriter::new(Vec::new(), RecordFormat::Text);
+        for (key, value) in records.iter() {
+            writer.write(key, value).unwrap();
+        }
+        assert_eq!(
+            writer.finish().unwrap(),
+            b"a\\:b:1\nhello:2\nmulti\\nline\\\\:x\\:y\\nz\n:\n"
+        );
+        assert_eq!(round_trip(RecordFormat::Text, &records), records);
+
+        for bad in [&b"no value\n"[..], b"a:b:c\n", b"a\\t:1\n"] {
+            let error = RecordReader::new(bad, RecordFormat::Text)
+                .next()
+                .unwrap()
+                .unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn test_lz4_round_trip() {
+        let records: Vec<Record> = (0..5000)
+            .map(|i| (format!("word{}", i % 50).into_bytes(), vec![1]))
+            .collect();
+        let (plain, plain_bytes) =
+            round_trip_compressed(RecordFormat::Binary, Compression::None, &records);
+        let (compressed, compressed_bytes) =
+            round_trip_compressed(RecordFormat::Binary, Compression::Lz4, &records);
+        assert_eq!(plain, records);
+        assert_eq!(compressed, records);
+        assert!(compressed_bytes * 4 < plain_bytes);
+    }
+
+    #[test]
+    fn test_corrupt_block() {
+        let mut writer = RecordWriter::new(Vec::new(), RecordFormat::Binary);
+        writer.write(b"hello", b"1").unwrap();
+        let mut bytes = writer.finish().unwrap();
+        *bytes.last_mut().unwrap() ^= 0xff;
+        let error = RecordReader::new(&bytes[..], RecordFormat::Binary)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_oversized_block() {
+        let too_big = (MAX_BLOCK_BYTES as u32 + 1).to_le_bytes();
+        // a length no reader should allocate for
+        let mut bytes = too_big.to_vec();
+        bytes.extend_from_slice(&[0; 4]);
+        let error = RecordReader::new(&bytes[..], RecordFormat::Binary)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        // a small block, checksum and all, claiming to decompress to too much
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&4u32.to_le_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&too_big).to_le_bytes());
+        bytes.extend_from_slice(&too_big);
+        let error = RecordReader::new(&bytes[..], RecordFormat::Binary)
+            .with_compression(Compression::Lz4)
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("larger than"));
+    }
+}
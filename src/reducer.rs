@@ -1,87 +1,644 @@
-use tokio::sync::{mpsc, oneshot};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+pub mod output;
+mod sort;
+pub use self::output::OutputFormat;
+use crate::job::ReduceFn;
+use crate::record::{self, Compression, RecordFormat, RecordReader};
+use crate::shuffle;
+use crate::transport::{Call, Transport};
+use crate::{Error, Result};
 
-pub struct Reducer {
-    receiver: mpsc::Receiver<ReducerMessage>,
+/// What a reducer produced for one partition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReduceSummary {
+    /// Number of distinct keys written.
+    pub keys: usize,
+    /// Number of intermediate values folded into those keys.
+    pub records: u64,
+    pub output: PathBuf,
+}
+
+pub struct Reducer<R: ReduceFn> {
+    receiver: mpsc::Receiver<Call<ReducerRequest, ReducerResponse>>,
     message_id: usize,
+    run_bytes: usize,
+    format: RecordFormat,
+    compression: Compression,
+    output_format: Arc<dyn OutputFormat>,
+    reduce_fn: Arc<R>,
 }
 
-pub enum ReducerMessage {
-    GetId { respond_to: oneshot::Sender<usize> },
-    Shuffle { respond_to : oneshot::Sender<String>, partition_name: String},
-    Reduce { respond_to : oneshot::Sender<String>, partition_name: String},
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReducerRequest {
+    GetId,
+    /// Sorts a partition. With no `sources` it is read where it is; otherwise
+    /// it is fetched from the shuffle server of every mapper in `sources` first.
+    /// Every `attempt` at a partition fetches and sorts into files of its own.
+    Shuffle {
+        partition_name: String,
+        sources: Vec<String>,
+        attempt: usize,
+    },
+    Reduce {
+        partition_name: String,
+        output: PathBuf,
+    },
+}
 
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReducerResponse {
+    Id(usize),
+    /// The sorted run a partition was shuffled into.
+    Shuffled(PathBuf),
+    Reduced(ReduceSummary),
 }
 
-impl Reducer {
-    fn new(receiver: mpsc::Receiver<ReducerMessage>) -> Self {
+impl<R: ReduceFn> Reducer<R> {
+    fn new(
+        receiver: mpsc::Receiver<Call<ReducerRequest, ReducerResponse>>,
+        run_bytes: usize,
+        format: RecordFormat,
+        compression: Compression,
+        output_format: Arc<dyn OutputFormat>,
+        reduce_fn: Arc<R>,
+    ) -> Self {
         Self {
             receiver,
             message_id: 0,
+            run_bytes,
+            format,
+            compression,
+            output_format,
+            reduce_fn,
         }
     }
 
-    fn handle_message(&mut self, msg: ReducerMessage) {
-        match msg {
-            ReducerMessage::GetId { respond_to } => {
+    async fn handle_message(&mut self, request: ReducerRequest) -> Result<ReducerResponse> {
+        match request {
+            ReducerRequest::GetId => {
                 self.message_id += 1;
-                let _ = respond_to.send(self.message_id);
+                Ok(ReducerResponse::Id(self.message_id))
             }
-            ReducerMessage:: Shuffle { respond_to, partition_name } => {
-                //this is going to go through each of the buffered file partitions and do a stable sort
+            ReducerRequest::Shuffle {
+                partition_name,
+                sources,
+                attempt,
+            } => {
+                // sort the partition into a run file next to it, spilling to disk
+                // whenever more than run_bytes of lines are held in memory
+                let input = Path::new(&partition_name);
+                let output = input.with_extension(format!("{}.sorted", attempt));
+                let (run_bytes, format, compression) =
+                    (self.run_bytes, self.format, self.compression);
+                let sorted = output.clone();
+                let sort = move |inputs: Vec<PathBuf>| {
+                    sort::external_sort(&inputs, &sorted, run_bytes, format, compression)
+                };
+                if sources.is_empty() {
+                    let inputs = partition_files(input)?;
+                    blocking(move || sort(inputs)).await?;
+                    return Ok(ReducerResponse::Shuffled(output));
+                }
+                let fetched = input.with_extension(format!("{}.fetched", attempt));
+                let partition = input.file_name().unwrap_or_default().to_string_lossy();
+                let mut inputs = Vec::new();
+                for (source, addr) in sources.iter().enumerate() {
+                    let name = format!("from{}", source);
+                    inputs.extend(
+                        shuffle::fetch_with_retries(addr, &partition, &fetched, &name).await?,
+                    );
+                }
+                blocking(move || {
+                    sort(inputs)?;
+                    std::fs::remove_dir_all(&fetched)
+                })
+                .await?;
+                Ok(ReducerResponse::Shuffled(output))
             }
-            ReducerMessage:: Reduce { respond_to, partition_name} => {
-                // this is going to consume each partition and return the counts of the words
+            ReducerRequest::Reduce {
+                partition_name,
+                output,
+            } => {
+                let reduce_fn = self.reduce_fn.clone();
+                let output_format = self.output_format.clone();
+                let (format, compression) = (self.format, self.compression);
+                let summary = blocking(move || {
+                    reduce_sorted(
+                        &*reduce_fn,
+                        Path::new(&partition_name),
+                        &output,
+                        format,
+                        compression,
+                        &*output_format,
+                    )
+                })
+                .await?;
+                Ok(ReducerResponse::Reduced(summary))
             }
         }
     }
 }
 
-async fn run_reducer(mut reducer: Reducer) {
-    while let Some(msg) = reducer.receiver.recv().await {
-        reducer.handle_message(msg);
+/// Runs a sort or a reduce on a thread meant for blocking work, so the tasks
+/// sharing the actor's runtime, like a worker's heartbeats and its shuffle
+/// server, go on meanwhile. A panic is passed on to the actor, which dies of
+/// it as it would have running the work itself.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> Result<T> {
+    match tokio::task::spawn_blocking(work).await {
+        Ok(result) => Ok(result?),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// The committed files of a partition directory, or just the file if the
+/// partition is a single file. Files still being written are left out.
+fn partition_files(partition: &Path) -> io::Result<Vec<PathBuf>> {
+    if !partition.is_dir() {
+        return Ok(vec![partition.to_path_buf()]);
+    }
+    let mut files = std::fs::read_dir(partition)?
+        .map(|res| res.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    files.retain(|path| path.extension().is_some_and(|ext| ext == "part"));
+    files.sort();
+    Ok(files)
+}
+
+/// Streams a sorted run of records, handing the values of identical keys that
+/// came from different mappers to the reduce function, and writes the result
+/// of every key in `output_format`.
+fn reduce_sorted<R: ReduceFn>(
+    reduce_fn: &R,
+    input: &Path,
+    output: &Path,
+    format: RecordFormat,
+    compression: Compression,
+    output_format: &dyn OutputFormat,
+) -> io::Result<ReduceSummary> {
+    let reader =
+        RecordReader::new(BufReader::new(File::open(input)?), format).with_compression(compression);
+    let mut writer = BufWriter::new(File::create(output)?);
+    output_format.begin(&mut writer)?;
+    let mut summary = ReduceSummary {
+        keys: 0,
+        records: 0,
+        output: output.to_path_buf(),
+    };
+    // the run is sorted, so all values of a key are adjacent and only one key's
+    // values are ever held in memory
+    let mut current: Option<(String, Vec<R::Value>)> = None;
+
+    for record in reader {
+        let (key, value) = record?;
+        let key = String::from_utf8(key).map_err(|e| invalid_record(&e.into_bytes()))?;
+        let value: R::Value =
+            record::decode_value(format, &value).ok_or_else(|| invalid_record(&value))?;
+        summary.records += 1;
+
+        match current {
+            Some((ref current_key, ref mut values)) if *current_key == key => {
+                values.push(value);
+            }
+            _ => {
+                if let Some((key, values)) = current.take() {
+                    write_reduced(reduce_fn, output_format, &mut writer, &key, values)?;
+                    summary.keys += 1;
+                }
+                current = Some((key, vec![value]));
+            }
+        }
+    }
+    if let Some((key, values)) = current {
+        write_reduced(reduce_fn, output_format, &mut writer, &key, values)?;
+        summary.keys += 1;
+    }
+    writer.flush()?;
+    Ok(summary)
+}
+
+fn write_reduced<R: ReduceFn>(
+    reduce_fn: &R,
+    output_format: &dyn OutputFormat,
+    writer: &mut impl Write,
+    key: &str,
+    values: Vec<R::Value>,
+) -> io::Result<()> {
+    let typed_key: R::Key = key.parse().map_err(|_| invalid_record(key.as_bytes()))?;
+    let result = reduce_fn.reduce(&typed_key, &mut values.into_iter());
+    output_format.write(writer, key, &result.to_string())
+}
+
+/// Merges partitions reduced into `output::Records` into a single file in
+/// `output_format`, sorted by the bytes of the keys' text.
+pub fn merge_outputs(
+    parts: &[PathBuf],
+    output: &Path,
+    output_format: &dyn OutputFormat,
+) -> io::Result<()> {
+    let readers = parts
+        .iter()
+        .map(|part| output::read_records(part))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut writer = BufWriter::new(File::create(output)?);
+    output_format.begin(&mut writer)?;
+    sort::merge(readers, |(key, result)| {
+        output_format.write(&mut writer, &key, &result)
+    })?;
+    writer.flush()
+}
+
+fn invalid_record(bytes: &[u8]) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "malformed partition record: {:?}",
+            String::from_utf8_lossy(bytes)
+        ),
+    )
+}
+
+async fn run_reducer<R: ReduceFn>(mut reducer: Reducer<R>) {
+    while let Some((request, respond_to)) = reducer.receiver.recv().await {
+        let _ = respond_to.send(reducer.handle_message(request).await);
     }
 }
 
 #[derive(Clone)]
 pub struct HandleReducer {
-    sender: mpsc::Sender<ReducerMessage>,
+    transport: Transport<ReducerRequest, ReducerResponse>,
+}
+
+fn unexpected(response: ReducerResponse) -> Error {
+    Error::Protocol(format!("unexpected reducer response {:?}", response))
 }
 
 impl HandleReducer {
-    pub fn new() -> Self {
+    pub fn new<R: ReduceFn>(reduce_fn: Arc<R>) -> Self {
+        Self::spawn(
+            reduce_fn,
+            sort::DEFAULT_RUN_BYTES,
+            RecordFormat::Binary,
+            Compression::None,
+            Arc::new(output::Tsv),
+        )
+    }
+
+    /// Spawns a reducer whose external sort keeps at most `run_bytes` of a
+    /// partition in memory at once.
+    pub fn with_run_bytes<R: ReduceFn>(reduce_fn: Arc<R>, run_bytes: usize) -> Self {
+        Self::spawn(
+            reduce_fn,
+            run_bytes,
+            RecordFormat::Binary,
+            Compression::None,
+            Arc::new(output::Tsv),
+        )
+    }
+
+    /// Spawns a reducer for partitions written in `format` with `compression`.
+    /// Its sorted runs are written the same way.
+    pub fn with_format<R: ReduceFn>(
+        reduce_fn: Arc<R>,
+        format: RecordFormat,
+        compression: Compression,
+    ) -> Self {
+        Self::spawn(
+            reduce_fn,
+            sort::DEFAULT_RUN_BYTES,
+            format,
+            compression,
+            Arc::new(output::Tsv),
+        )
+    }
+
+    /// Like `with_format`, but writes the reduced results in `output_format`.
+    pub fn with_output_format<R: ReduceFn>(
+        reduce_fn: Arc<R>,
+        format: RecordFormat,
+        compression: Compression,
+        output_format: Arc<dyn OutputFormat>,
+    ) -> Self {
+        Self::spawn(
+            reduce_fn,
+            sort::DEFAULT_RUN_BYTES,
+            format,
+            compression,
+            output_format,
+        )
+    }
+
+    fn spawn<R: ReduceFn>(
+        reduce_fn: Arc<R>,
+        run_bytes: usize,
+        format: RecordFormat,
+        compression: Compression,
+        output_format: Arc<dyn OutputFormat>,
+    ) -> Self {
         let (sender, receiver) = mpsc::channel(8);
-        let reducer: Reducer = Reducer::new(receiver);
+        let reducer: Reducer<R> = Reducer::new(
+            receiver,
+            run_bytes,
+            format,
+            compression,
+            output_format,
+            reduce_fn,
+        );
         tokio::spawn(run_reducer(reducer));
 
-        Self { sender }
+        Self::with_transport(Transport::local("reducer", sender))
     }
 
-    pub async fn get_unique_id(self) -> usize {
-        let (send, recv) = oneshot::channel();
-        let message = ReducerMessage::GetId { respond_to: send };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Reduce Actor died")
+    /// A handle to a reducer reached through `transport`, e.g. one served on a socket.
+    pub fn with_transport(transport: Transport<ReducerRequest, ReducerResponse>) -> Self {
+        Self { transport }
     }
-     pub async fn shuffle(self, partition_name: String) {
-        let (send, recv) = oneshot::channel();
-        let message = ReducerMessage::Shuffle {
-            respond_to: send,
-            partition_name
+
+    /// Where the reducer behind this handle takes its calls, to serve it elsewhere.
+    pub fn transport(&self) -> Transport<ReducerRequest, ReducerResponse> {
+        self.transport.clone()
+    }
+
+    pub async fn get_unique_id(self) -> Result<usize> {
+        match self.transport.call(ReducerRequest::GetId).await? {
+            ReducerResponse::Id(id) => Ok(id),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Externally sorts a partition, either a single file or a directory of
+    /// them, returning the path of the sorted run.
+    pub async fn shuffle(self, partition_name: String) -> Result<PathBuf> {
+        self.shuffle_from(partition_name, Vec::new(), 0).await
+    }
+
+    /// Fetches a partition from the shuffle server of every mapper in
+    /// `sources` and externally sorts what they sent, returning the path of
+    /// the sorted run. The run goes where the partition would be locally, in
+    /// a file named after `attempt`, so attempts at a partition that overlap
+    /// never write the same files.
+    pub async fn shuffle_from(
+        self,
+        partition_name: String,
+        sources: Vec<String>,
+        attempt: usize,
+    ) -> Result<PathBuf> {
+        let request = ReducerRequest::Shuffle {
+            partition_name,
+            sources,
+            attempt,
         };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Reduce ExternalSort Dead");
-     }
+        match self.transport.call(request).await? {
+            ReducerResponse::Shuffled(sorted) => Ok(sorted),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Reduces a sorted run produced by `shuffle` into a file at `output`, in
+    /// the reducer's output format.
+    pub async fn reduce(self, partition_name: String, output: PathBuf) -> Result<ReduceSummary> {
+        let request = ReducerRequest::Reduce {
+            partition_name,
+            output,
+        };
+        match self.transport.call(request).await? {
+            ReducerResponse::Reduced(summary) => Ok(summary),
+            other => Err(unexpected(other)),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::job::WordCount;
+    use crate::record::RecordWriter;
 
     #[tokio::test]
     async fn test_get_unique_id() {
-        let reducer = HandleReducer::new();
-        let id = reducer.get_unique_id().await;
+        let reducer = HandleReducer::new(Arc::new(WordCount));
+        let id = reducer.get_unique_id().await.unwrap();
         assert_eq!(id, 1);
     }
 
+    #[tokio::test]
+    async fn test_shuffle() {
+        let dir = std::env::temp_dir().join(format!("shuffle-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let partition = dir.join("3");
+        std::fs::create_dir_all(&partition).unwrap();
+        for (name, records) in [
+            ("0.part", vec![("one", 1u32), ("three", 1)]),
+            ("1.part", vec![("other", 2), ("one", 4)]),
+        ] {
+            let file = File::create(partition.join(name)).unwrap();
+            let mut writer = RecordWriter::new(file, RecordFormat::Binary);
+            for (key, value) in records {
+                let mut encoded = Vec::new();
+                record::encode_value(RecordFormat::Binary, &value, &mut encoded);
+                writer.write(key.as_bytes(), &encoded).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+        // an unfinished file is left out
+        std::fs::write(partition.join("2.part.tmp"), "garbage").unwrap();
+
+        let reducer = HandleReducer::with_run_bytes(Arc::new(WordCount), 10);
+        let sorted = reducer
+            .clone()
+            .shuffle(partition.to_string_lossy().into_owned())
+            .await
+            .unwrap();
+        assert_eq!(sorted, dir.join("3.0.sorted"));
+        let summary = reducer
+            .reduce(sorted.to_string_lossy().into_owned(), dir.join("3.tsv"))
+            .await
+            .unwrap();
+        assert_eq!(summary.records, 4);
+        let contents = std::fs::read_to_string(dir.join("3.tsv")).unwrap();
+        assert_eq!(contents, "one\t5\nother\t2\nthree\t1\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shuffle_from_mappers() {
+        let dir = std::env::temp_dir().join(format!("shuffle-from-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // two mappers, each with its own scratch directory and shuffle server
+        let mut sources = Vec::new();
+        let mut servers = Vec::new();
+        for (mapper, records) in [
+            ("a", vec![("one", 1u32), ("three", 1)]),
+            ("b", vec![("other", 2), ("one", 4)]),
+        ] {
+            let partition = dir.join(mapper).join("3");
+            std::fs::create_dir_all(&partition).unwrap();
+            let file = File::create(partition.join("w0-1.part")).unwrap();
+            let mut writer = RecordWriter::new(file, RecordFormat::Binary);
+            for (key, value) in records {
+                let mut encoded = Vec::new();
+                record::encode_value(RecordFormat::Binary, &value, &mut encoded);
+                writer.write(key.as_bytes(), &encoded).unwrap();
+            }
+            writer.finish().unwrap();
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            sources.push(listener.local_addr().unwrap().to_string());
+            let scratch_dir = dir.join(mapper);
+            servers.push(tokio::spawn(shuffle::serve(
+                listener,
+                scratch_dir,
+                String::from("w0-"),
+            )));
+        }
+
+        let reducer = HandleReducer::with_run_bytes(Arc::new(WordCount), 10);
+        let partition = dir.join("reducer").join("3");
+        let sorted = reducer
+            .clone()
+            .shuffle_from(partition.to_string_lossy().into_owned(), sources, 2)
+            .await
+            .unwrap();
+        assert_eq!(sorted, dir.join("reducer").join("3.2.sorted"));
+        // what was fetched is gone once it is sorted
+        assert!(!dir.join("reducer").join("3.2.fetched").exists());
+        let summary = reducer
+            .reduce(sorted.to_string_lossy().into_owned(), dir.join("3.tsv"))
+            .await
+            .unwrap();
+        assert_eq!(summary.records, 4);
+        let contents = std::fs::read_to_string(dir.join("3.tsv")).unwrap();
+        assert_eq!(contents, "one\t5\nother\t2\nthree\t1\n");
+
+        for server in servers {
+            server.abort();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reduce() {
+        let dir = std::env::temp_dir().join(format!("reduce-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sorted = dir.join("2.sorted");
+        std::fs::write(&sorted, "a\\:b:1\nhello:2\nhello:3\nhi:1\n").unwrap();
+
+        let reducer =
+            HandleReducer::with_format(Arc::new(WordCount), RecordFormat::Text, Compression::None);
+        let summary = reducer
+            .reduce(sorted.to_string_lossy().into_owned(), dir.join("2.tsv"))
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            ReduceSummary {
+                keys: 3,
+                records: 4,
+                output: dir.join("2.tsv"),
+            }
+        );
+        let contents = std::fs::read_to_string(dir.join("2.tsv")).unwrap();
+        assert_eq!(contents, "a:b\t1\nhello\t5\nhi\t1\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct Panicky;
+
+    impl ReduceFn for Panicky {
+        type Key = String;
+        type Value = u32;
+        type Output = u32;
+
+        fn reduce(&self, _: &String, _: &mut dyn Iterator<Item = u32>) -> u32 {
+            panic!("bad reduce function");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reduce_panics() {
+        let dir = std::env::temp_dir().join(format!("reduce-panic-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sorted = dir.join("0.sorted");
+        std::fs::write(&sorted, "hello:2\n").unwrap();
+
+        // the panic happens away from the actor, which still dies of it
+        let reducer =
+            HandleReducer::with_format(Arc::new(Panicky), RecordFormat::Text, Compression::None);
+        let result = reducer
+            .reduce(sorted.to_string_lossy().into_owned(), dir.join("0.tsv"))
+            .await;
+        assert!(matches!(result, Err(Error::ActorGone("reducer"))));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reduce_over_stream() {
+        let dir = std::env::temp_dir().join(format!("remote-reduce-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sorted = dir.join("0.sorted");
+        std::fs::write(&sorted, "hello:2\nhello:3\n").unwrap();
+
+        let reducer =
+            HandleReducer::with_format(Arc::new(WordCount), RecordFormat::Text, Compression::None);
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(crate::transport::serve(server, reducer.transport()));
+        let remote = HandleReducer::with_transport(Transport::connect("reducer", client));
+        let summary = remote
+            .clone()
+            .reduce(sorted.to_string_lossy().into_owned(), dir.join("0.tsv"))
+            .await
+            .unwrap();
+        assert_eq!(summary.keys, 1);
+        assert_eq!(
+            std::fs::read_to_string(dir.join("0.tsv")).unwrap(),
+            "hello\t5\n"
+        );
+        assert!(matches!(
+            remote
+                .reduce(
+                    dir.join("missing").to_string_lossy().into_owned(),
+                    dir.join("1.tsv")
+                )
+                .await,
+            Err(Error::Io(_))
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_merge_outputs() {
+        let dir = std::env::temp_dir().join(format!("merge-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut parts = Vec::new();
+        for (name, results) in [
+            ("part-1.records", [("apple", "2"), ("zoo", "1")]),
+            ("part-2.records", [("banana", "4"), ("mango", "1")]),
+        ] {
+            let mut part = Vec::new();
+            for (key, result) in results {
+                output::Records.write(&mut part, key, result).unwrap();
+            }
+            std::fs::write(dir.join(name), part).unwrap();
+            parts.push(dir.join(name));
+        }
+
+        merge_outputs(&parts, &dir.join("wordcount.tsv"), &output::Tsv).unwrap();
+        let contents = std::fs::read_to_string(dir.join("wordcount.tsv")).unwrap();
+        assert_eq!(contents, "apple\t2\nbanana\t4\nmango\t1\nzoo\t1\n");
+
+        merge_outputs(&parts, &dir.join("wordcount.csv"), &output::Csv).unwrap();
+        let contents = std::fs::read_to_string(dir.join("wordcount.csv")).unwrap();
+        assert_eq!(contents, "key,value\napple,2\nbanana,4\nmango,1\nzoo,1\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
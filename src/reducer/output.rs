@@ -0,0 +1,186 @@
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader};
+use std::path::Path;
+
+/// How the reduced result of every key is written out.
+pub trait OutputFormat: Send + Sync + 'static {
+    /// Extension of the files it writes, without the dot.
+    fn extension(&self) -> &'static str;
+
+    /// Writes whatever a file starts with, e.g. a header row.
+    fn begin(&self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Writes the result of one key.
+    fn write(&self, out: &mut dyn Write, key: &str, output: &str) -> io::Result<()>;
+}
+
+/// `key\toutput` lines. Backslashes, tabs and line breaks in either are
+/// escaped as `\\`, `\t`, `\n` and `\r`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tsv;
+
+impl OutputFormat for Tsv {
+    fn extension(&self) -> &'static str {
+        "tsv"
+    }
+
+    fn write(&self, out: &mut dyn Write, key: &str, output: &str) -> io::Result<()> {
+        writeln!(out, "{}\t{}", escape_tsv(key), escape_tsv(output))
+    }
+}
+
+fn escape_tsv(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Comma separated `key,value` rows under a header row, quoted where needed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Csv;
+
+impl OutputFormat for Csv {
+    fn extension(&self) -> &'static str {
+        "csv"
+    }
+
+    fn begin(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "key,value")
+    }
+
+    fn write(&self, out: &mut dyn Write, key: &str, output: &str) -> io::Result<()> {
+        writeln!(out, "{},{}", quote_csv(key), quote_csv(output))
+    }
+}
+
+fn quote_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// One `{"key": ..., "value": ...}` object per line. A result that reads as a
+/// number is written as a JSON number, anything else as a string.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonLines;
+
+impl OutputFormat for JsonLines {
+    fn extension(&self) -> &'static str {
+        "jsonl"
+    }
+
+    fn write(&self, out: &mut dyn Write, key: &str, output: &str) -> io::Result<()> {
+        let value = match output.parse::<serde_json::Number>() {
+            Ok(number) => serde_json::Value::Number(number),
+            Err(_) => serde_json::Value::String(output.to_string()),
+        };
+        let line = serde_json::json!({ "key": key, "value": value });
+        writeln!(out, "{}", line)
+    }
+}
+
+/// Key/result pairs with their lengths in front, which `read_records` reads
+/// back. Partitions are reduced into this when their results are merged into
+/// a single file afterwards.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Records;
+
+impl OutputFormat for Records {
+    fn extension(&self) -> &'static str {
+        "records"
+    }
+
+    fn write(&self, out: &mut dyn Write, key: &str, output: &str) -> io::Result<()> {
+        for field in [key, output] {
+            out.write_all(&(field.len() as u32).to_le_bytes())?;
+            out.write_all(field.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a file written with `Records`.
+pub fn read_records(path: &Path) -> io::Result<impl Iterator<Item = io::Result<(String, String)>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    Ok(std::iter::from_fn(move || {
+        match reader.fill_buf() {
+            Ok([]) => return None,
+            Ok(_) => {}
+            Err(e) => return Some(Err(e)),
+        }
+        Some(read_field(&mut reader).and_then(|key| Ok((key, read_field(&mut reader)?))))
+    }))
+}
+
+fn read_field(reader: &mut impl Read) -> io::Result<String> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut field = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut field)?;
+    String::from_utf8(field).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn written(format: &dyn OutputFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        format.begin(&mut out).unwrap();
+        for (key, output) in [("cat", "3"), ("say \"hi\", tab\there", "a\nb")] {
+            format.write(&mut out, key, output).unwrap();
+        }
+        out
+    }
+
+    fn written_text(format: &dyn OutputFormat) -> String {
+        String::from_utf8(written(format)).unwrap()
+    }
+
+    #[test]
+    fn test_output_formats() {
+        assert_eq!(
+            written_text(&Tsv),
+            "cat\t3\nsay \"hi\", tab\\there\ta\\nb\n"
+        );
+        assert_eq!(
+            written_text(&Csv),
+            "key,value\ncat,3\n\"say \"\"hi\"\", tab\there\",\"a\nb\"\n"
+        );
+        assert_eq!(
+            written_text(&JsonLines),
+            "{\"key\":\"cat\",\"value\":3}\n\
+             {\"key\":\"say \\\"hi\\\", tab\\there\",\"value\":\"a\\nb\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_records_round_trip() {
+        let path = std::env::temp_dir().join(format!("records-{}.records", std::process::id()));
+        std::fs::write(&path, written(&Records)).unwrap();
+        let records = read_records(&path)
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (String::from("cat"), String::from("3")),
+                (String::from("say \"hi\", tab\there"), String::from("a\nb")),
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use crate::record::{Compression, Record, RecordFormat, RecordReader, RecordWriter};
+
+/// Default amount of partition data (in bytes) held in memory per sorted run.
+pub const DEFAULT_RUN_BYTES: usize = 16 * 1024 * 1024;
+
+/// Most runs merged at once. Every run being merged holds a file and a read
+/// buffer open, so the runs of a partition that spills thousands are merged
+/// in several passes, each merging groups of runs into longer ones.
+pub const MERGE_FAN_IN: usize = 64;
+
+/// Sorts the records of all `inputs` by the bytes of their keys into `output`
+/// without ever holding more than roughly `run_bytes` of records in memory.
+///
+/// The input is cut into runs that are sorted in memory and spilled next to
+/// the output, then the runs are k-way merged, no more than `MERGE_FAN_IN` at
+/// a time. Returns the number of records written.
+pub fn external_sort(
+    inputs: &[PathBuf],
+    output: &Path,
+    run_bytes: usize,
+    format: RecordFormat,
+    compression: Compression,
+) -> io::Result<usize> {
+    let mut runs: Vec<PathBuf> = Vec::new();
+    let mut chunk: Vec<Record> = Vec::new();
+    let mut chunk_bytes = 0;
+    let mut records = 0;
+
+    for input in inputs.iter() {
+        for record in open_records(input, format, compression)? {
+            let record = record?;
+            records += 1;
+            chunk_bytes += record.0.len() + record.1.len();
+            chunk.push(record);
+            if chunk_bytes >= run_bytes {
+                runs.push(spill_run(
+                    &mut chunk,
+                    output,
+                    runs.len(),
+                    format,
+                    compression,
+                )?);
+                chunk_bytes = 0;
+            }
+        }
+    }
+    if !chunk.is_empty() || runs.is_empty() {
+        runs.push(spill_run(
+            &mut chunk,
+            output,
+            runs.len(),
+            format,
+            compression,
+        )?);
+    }
+
+    let mut pass = 0;
+    while runs.len() > MERGE_FAN_IN {
+        pass += 1;
+        let mut merged = Vec::new();
+        for group in runs.chunks(MERGE_FAN_IN) {
+            let path = output.with_extension(format!("merge{}-{}", pass, merged.len()));
+            merge_runs(group, &path, format, compression)?;
+            merged.push(path);
+        }
+        runs = merged;
+    }
+    merge_runs(&runs, output, format, compression)?;
+    Ok(records)
+}
+
+/// Merges sorted runs into `output`, removing them once they are merged.
+fn merge_runs(
+    runs: &[PathBuf],
+    output: &Path,
+    format: RecordFormat,
+    compression: Compression,
+) -> io::Result<()> {
+    if let [run] = runs {
+        return fs::rename(run, output);
+    }
+    let readers = runs
+        .iter()
+        .map(|run| open_records(run, format, compression))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut writer = create_records(output, format, compression)?;
+    merge(readers, |(key, value)| writer.write(&key, &value))?;
+    writer.finish()?;
+    for run in runs {
+        fs::remove_file(run)?;
+    }
+    Ok(())
+}
+
+fn spill_run(
+    chunk: &mut Vec<Record>,
+    output: &Path,
+    index: usize,
+    format: RecordFormat,
+    compression: Compression,
+) -> io::Result<PathBuf> {
+    chunk.sort();
+    let path = output.with_extension(format!("run{}", index));
+    let mut writer = create_records(&path, format, compression)?;
+    for (key, value) in chunk.drain(..) {
+        writer.write(&key, &value)?;
+    }
+    writer.finish()?;
+    Ok(path)
+}
+
+fn open_records(
+    path: &Path,
+    format: RecordFormat,
+    compression: Compression,
+) -> io::Result<RecordReader<BufReader<File>>> {
+    let reader = RecordReader::new(BufReader::new(File::open(path)?), format);
+    Ok(reader.with_compression(compression))
+}
+
+fn create_records(
+    path: &Path,
+    format: RecordFormat,
+    compression: Compression,
+) -> io::Result<RecordWriter<BufWriter<File>>> {
+    let writer = RecordWriter::new(BufWriter::new(File::create(path)?), format);
+    Ok(writer.with_compression(compression))
+}
+
+struct HeapEntry<T> {
+    item: T,
+    source: usize,
+}
+
+impl<T: Ord> Ord for HeapEntry<T> {
+    // BinaryHeap is a max heap, so the comparison is reversed to pop the smallest item
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .item
+            .cmp(&self.item)
+            .then_with(|| other.source.cmp(&self.source))
+    }
+}
+
+impl<T: Ord> PartialOrd for HeapEntry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Ord> PartialEq for HeapEntry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<T: Ord> Eq for HeapEntry<T> {}
+
+/// K-way merges already sorted sources, handing every item to `emit` in order.
+pub fn merge<T, I>(mut sources: Vec<I>, mut emit: impl FnMut(T) -> io::Result<()>) -> io::Result<()>
+where
+    T: Ord,
+    I: Iterator<Item = io::Result<T>>,
+{
+    let mut heap = BinaryHeap::with_capacity(sources.len());
+    for (source, items) in sources.iter_mut().enumerate() {
+        if let Some(item) = items.next() {
+            heap.push(HeapEntry {
+                item: item?,
+                source,
+            });
+        }
+    }
+    while let Some(HeapEntry { item, source }) = heap.pop() {
+        emit(item)?;
+        if let Some(next) = sources[source].next() {
+            heap.push(HeapEntry {
+                item: next?,
+                source,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_external_sort_multiple_runs() {
+        let dir = std::env::temp_dir().join(format!("sort-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let inputs = [dir.join("1.part"), dir.join("2.part")];
+        let output = dir.join("1.sorted");
+        for (input, records) in inputs.iter().zip([
+            [("pear", "1"), ("apple", "2"), ("zebra", "1")],
+            [("apple", "1"), ("mango", "3"), ("banana", "1")],
+        ]) {
+            let mut writer = create_records(input, RecordFormat::Binary, Compression::Lz4).unwrap();
+            for (key, value) in records {
+                writer.write(key.as_bytes(), value.as_bytes()).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+
+        // a tiny run size forces one run per couple of records
+        let records =
+            external_sort(&inputs, &output, 8, RecordFormat::Binary, Compression::Lz4).unwrap();
+        assert_eq!(records, 6);
+
+        let reader = open_records(&output, RecordFormat::Binary, Compression::Lz4).unwrap();
+        let sorted: Vec<String> = reader
+            .map(|record| {
+                let (key, value) = record.unwrap();
+                format!(
+                    "{}:{}",
+                    String::from_utf8(key).unwrap(),
+                    String::from_utf8(value).unwrap()
+                )
+            })
+            .collect();
+        assert_eq!(
+            sorted,
+            vec!["apple:1", "apple:2", "banana:1", "mango:3", "pear:1", "zebra:1"]
+        );
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        assert_eq!(leftovers, 3);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_external_sort_merges_in_passes() {
+        let dir = std::env::temp_dir().join(format!("sort-passes-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("1.part");
+        let output = dir.join("1.sorted");
+        let count = 3 * MERGE_FAN_IN + 5;
+        let mut writer = create_records(&input, RecordFormat::Binary, Compression::None).unwrap();
+        for i in 0..count {
+            // shuffled, so no run comes out in order by chance
+            let key = format!("key{:04}", i * 7919 % count);
+            writer.write(key.as_bytes(), b"1").unwrap();
+        }
+        writer.finish().unwrap();
+
+        // a run per record, more than can be merged in one pass
+        let records = external_sort(
+            &[input],
+            &output,
+            1,
+            RecordFormat::Binary,
+            Compression::None,
+        )
+        .unwrap();
+        assert_eq!(records, count);
+
+        let reader = open_records(&output, RecordFormat::Binary, Compression::None).unwrap();
+        let keys: Vec<String> = reader
+            .map(|record| String::from_utf8(record.unwrap().0).unwrap())
+            .collect();
+        let expected: Vec<String> = (0..count).map(|i| format!("key{:04}", i)).collect();
+        assert_eq!(keys, expected);
+        // only the input and the output are left
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,334 @@
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::transport::{self, WireError};
+use crate::{Error, Result};
+
+/// Times a reducer tries to fetch a partition from one mapper before giving up.
+pub const FETCH_ATTEMPTS: usize = 3;
+
+/// How long a reducer waits before its first retry; doubled for every retry after.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+
+/// What a shuffle server is asked for, one request per connection.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FetchRequest {
+    /// Everything the worker drained into a partition, by the partition's
+    /// directory name, e.g. `"3"`.
+    Partition(String),
+    /// What the worker's `attempt` at reducing a partition left in its
+    /// `reduced_file`.
+    Reduced { partition: String, attempt: usize },
+}
+
+/// What a shuffle server answers a fetch with, a frame at a time.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum FetchReply {
+    /// A file asked for, whose `bytes` follow the frame as they are.
+    Part {
+        bytes: u64,
+    },
+    /// Every file asked for was sent.
+    End,
+    Failed(WireError),
+}
+
+/// Serves the map output in `scratch_dir` to the reducers that connect to
+/// `listener`, one connection per fetch, and the reduced partitions to the
+/// coordinator. Only the committed drain files whose names start with `prefix`
+/// are served, so workers sharing a scratch directory each serve their own.
+pub async fn serve(listener: TcpListener, scratch_dir: PathBuf, prefix: String) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("could not accept a fetch: {}", e);
+                continue;
+            }
+        };
+        let scratch_dir = scratch_dir.clone();
+        let prefix = prefix.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_fetch(stream, &scratch_dir, &prefix).await {
+                eprintln!("fetch from {} failed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn serve_fetch(mut stream: TcpStream, scratch_dir: &Path, prefix: &str) -> Result<()> {
+    let Some((id, request)) = transport::read_frame(&mut stream).await? else {
+        return Ok(());
+    };
+    let files = match request {
+        FetchRequest::Partition(partition) => drains(&scratch_dir.join(partition), prefix),
+        FetchRequest::Reduced { partition, attempt } => {
+            let path = reduced_file(scratch_dir, &partition, attempt);
+            if path.is_file() {
+                Ok(vec![path])
+            } else {
+                Err(Error::Protocol(format!(
+                    "{} was never reduced here",
+                    path.display()
+                )))
+            }
+        }
+    };
+    let files = match files {
+        Ok(files) => files,
+        Err(e) => {
+            let reply = FetchReply::Failed(WireError::from(&e));
+            return transport::write_frame(&mut stream, id, &reply).await;
+        }
+    };
+    for path in files {
+        // a file that fails halfway leaves the reducer with a short read, which
+        // it retries like any other broken fetch
+        let mut file = File::open(&path).await?;
+        let bytes = file.metadata().await?.len();
+        transport::write_frame(&mut stream, id, &FetchReply::Part { bytes }).await?;
+        let copied = tokio::io::copy(&mut (&mut file).take(bytes), &mut stream).await?;
+        if copied != bytes {
+            return Err(Error::Protocol(format!(
+                "{} shrank while it was sent",
+                path.display()
+            )));
+        }
+    }
+    transport::write_frame(&mut stream, id, &FetchReply::End).await
+}
+
+/// The committed drain files in `partition` whose names start with `prefix`. A
+/// partition this worker never drained into is empty.
+fn drains(partition: &Path, prefix: &str) -> Result<Vec<PathBuf>> {
+    if !partition.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(partition).map_err(Error::DirectoryReadError)? {
+        let path = entry.map_err(Error::DirectoryReadError)?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.starts_with(prefix) && name.ends_with(".part") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Where a worker's `attempt` at reducing `partition` leaves the result, for
+/// the coordinator to fetch.
+pub fn reduced_file(scratch_dir: &Path, partition: &str, attempt: usize) -> PathBuf {
+    scratch_dir.join(format!("{}.{}.reduced", partition, attempt))
+}
+
+/// Fetches `partition` from the shuffle server at `addr` into `dir`, as files
+/// named `{name}-{n}.part`, and returns them. Files left by an earlier, broken
+/// attempt are removed first, and a file only gets its name once it arrived whole.
+pub async fn fetch(addr: &str, partition: &str, dir: &Path, name: &str) -> Result<Vec<PathBuf>> {
+    let request = FetchRequest::Partition(partition.to_string());
+    let mut stream = request_from(addr, &request).await?;
+    tokio::fs::create_dir_all(dir).await?;
+    let prefix = format!("{}-", name);
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        if entry.file_name().to_string_lossy().starts_with(&prefix) {
+            tokio::fs::remove_file(entry.path()).await?;
+        }
+    }
+    let mut files = Vec::new();
+    while let Some(bytes) = next_file(&mut stream, addr).await? {
+        let path = dir.join(format!("{}-{}.part", name, files.len()));
+        receive(
+            &mut stream,
+            addr,
+            bytes,
+            &path.with_extension("fetching"),
+            &path,
+        )
+        .await?;
+        files.push(path);
+    }
+    Ok(files)
+}
+
+/// Fetches what a worker's `attempt` at reducing `partition` left, from its
+/// shuffle server at `addr`, into `output`. Attempts fetched at the same time
+/// each write a file of their own until theirs arrived whole.
+pub async fn fetch_reduced(
+    addr: &str,
+    partition: &str,
+    attempt: usize,
+    output: &Path,
+) -> Result<()> {
+    let request = FetchRequest::Reduced {
+        partition: partition.to_string(),
+        attempt,
+    };
+    let mut stream = request_from(addr, &request).await?;
+    let Some(bytes) = next_file(&mut stream, addr).await? else {
+        return Err(Error::Protocol(format!("{} sent no result", addr)));
+    };
+    let mut partial = output.to_path_buf().into_os_string();
+    partial.push(format!(".{}.fetching", attempt));
+    receive(&mut stream, addr, bytes, Path::new(&partial), output).await?;
+    match next_file(&mut stream, addr).await? {
+        None => Ok(()),
+        Some(_) => Err(Error::Protocol(format!(
+            "{} sent more than one result",
+            addr
+        ))),
+    }
+}
+
+async fn request_from(addr: &str, request: &FetchRequest) -> Result<TcpStream> {
+    let mut stream = TcpStream::connect(addr).await?;
+    transport::write_frame(&mut stream, 0, request).await?;
+    Ok(stream)
+}
+
+/// The size of the next file a shuffle server sends, or `None` once it sent
+/// all of them.
+async fn next_file(stream: &mut TcpStream, addr: &str) -> Result<Option<u64>> {
+    let reply = transport::read_frame(stream).await?;
+    match reply.map(|(_, reply)| reply) {
+        Some(FetchReply::Part { bytes }) => Ok(Some(bytes)),
+        Some(FetchReply::End) => Ok(None),
+        Some(FetchReply::Failed(e)) => Err(e.into()),
+        None => Err(Error::Protocol(format!(
+            "{} hung up in the middle of a fetch",
+            addr
+        ))),
+    }
+}
+
+/// Copies the next `bytes` of `stream` into `partial`, and renames it to
+/// `path` once they all arrived.
+async fn receive(
+    stream: &mut TcpStream,
+    addr: &str,
+    bytes: u64,
+    partial: &Path,
+    path: &Path,
+) -> Result<()> {
+    let mut writer = BufWriter::new(File::create(partial).await?);
+    let copied = tokio::io::copy(&mut stream.take(bytes), &mut writer).await?;
+    writer.flush().await?;
+    if copied != bytes {
+        return Err(Error::Protocol(format!(
+            "{} sent {} of {} bytes",
+            addr, copied, bytes
+        )));
+    }
+    tokio::fs::rename(partial, path).await?;
+    Ok(())
+}
+
+/// Like `fetch`, but tries up to `FETCH_ATTEMPTS` times, backing off between
+/// attempts, before giving up on the mapper at `addr` with `Error::FetchFailed`.
+pub async fn fetch_with_retries(
+    addr: &str,
+    partition: &str,
+    dir: &Path,
+    name: &str,
+) -> Result<Vec<PathBuf>> {
+    let mut backoff = RETRY_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        match fetch(addr, partition, dir, name).await {
+            Ok(files) => return Ok(files),
+            Err(e) if attempt < FETCH_ATTEMPTS => {
+                eprintln!(
+                    "Failed to fetch partition {} from {} (attempt {}): {}, retrying",
+                    partition, addr, attempt, e
+                );
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(Error::FetchFailed {
+                    addr: addr.to_string(),
+                    message: format!(
+                        "partition {} failed after {} attempt(s): {}",
+                        partition, attempt, e
+                    ),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let dir = test_dir("shuffle-server-test");
+        let partition = dir.join("scratch").join("1");
+        std::fs::create_dir_all(&partition).unwrap();
+        std::fs::write(partition.join("w0-1.part"), "first drain").unwrap();
+        std::fs::write(partition.join("w0-2.part"), "").unwrap();
+        // another worker's drain, and one still being written
+        std::fs::write(partition.join("w1-3.part"), "not mine").unwrap();
+        std::fs::write(partition.join("w0-4.tmp"), "not done").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = tokio::spawn(serve(listener, dir.join("scratch"), String::from("w0-")));
+
+        let fetched = dir.join("fetched");
+        let files = fetch(&addr, "1", &fetched, "from0").await.unwrap();
+        assert_eq!(
+            files,
+            vec![fetched.join("from0-0.part"), fetched.join("from0-1.part")]
+        );
+        assert_eq!(std::fs::read_to_string(&files[0]).unwrap(), "first drain");
+        assert_eq!(std::fs::read_to_string(&files[1]).unwrap(), "");
+        // nothing was drained into partition 0
+        assert!(fetch(&addr, "0", &fetched, "from0")
+            .await
+            .unwrap()
+            .is_empty());
+
+        std::fs::write(reduced_file(&dir.join("scratch"), "1", 2), "hello\t2\n").unwrap();
+        let output = dir.join("part-1.tsv");
+        fetch_reduced(&addr, "1", 2, &output).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "hello\t2\n");
+        // an attempt that left nothing
+        assert!(fetch_reduced(&addr, "1", 3, &output).await.is_err());
+
+        server.abort();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_fetch_gives_up() {
+        let dir = test_dir("shuffle-server-gone-test");
+        // a port nothing listens on any more
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let result = fetch_with_retries(&addr, "0", &dir, "from0").await;
+        assert!(matches!(
+            result,
+            Err(Error::FetchFailed { addr: failed, message })
+                if failed == addr && message.contains("3 attempt(s)")
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+use crate::{Error, Result};
+
+/// Version of the wire format. Frames of any other version are refused.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Frames longer than this are refused instead of read into memory.
+pub const MAX_FRAME_BYTES: usize = 256 * 1024 * 1024;
+
+/// A request on its way to an actor, along with where its reply goes.
+pub type Call<Req, Resp> = (Req, oneshot::Sender<Result<Resp>>);
+
+#[derive(Serialize, Deserialize)]
+struct Frame<T> {
+    version: u32,
+    id: u64,
+    body: T,
+}
+
+// read on its own first, so a frame of another version is refused before its
+// body fails to decode
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+/// Writes `body` as one frame: its length as a big-endian u32, then the frame
+/// in bincode, tagged with the protocol version and `id`. Bincode keeps record
+/// payloads at their own size, where JSON would spell out every byte.
+pub async fn write_frame<W, T>(writer: &mut W, id: u64, body: &T) -> Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+    T: Serialize,
+{
+    let frame = Frame {
+        version: PROTOCOL_VERSION,
+        id,
+        body,
+    };
+    let bytes = bincode::serialize(&frame).map_err(|e| Error::Protocol(e.to_string()))?;
+    if bytes.len() > MAX_FRAME_BYTES {
+        return Err(Error::Protocol(format!(
+            "frame of {} bytes is over the limit of {}",
+            bytes.len(),
+            MAX_FRAME_BYTES
+        )));
+    }
+    writer
+        .write_all(&(bytes.len() as u32).to_be_bytes())
+        .await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads the next frame, or `None` if the stream ends before one starts.
+pub async fn read_frame<R, T>(reader: &mut R) -> Result<Option<(u64, T)>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    T: DeserializeOwned,
+{
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(Error::Io(e)),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(Error::Protocol(format!(
+            "frame of {} bytes is over the limit of {}",
+            len, MAX_FRAME_BYTES
+        )));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).await?;
+    let Version { version } =
+        bincode::deserialize(&bytes).map_err(|e| Error::Protocol(e.to_string()))?;
+    if version != PROTOCOL_VERSION {
+        return Err(Error::Protocol(format!(
+            "peer speaks protocol version {}, expected {}",
+            version, PROTOCOL_VERSION
+        )));
+    }
+    let frame: Frame<T> =
+        bincode::deserialize(&bytes).map_err(|e| Error::Protocol(e.to_string()))?;
+    Ok(Some((frame.id, frame.body)))
+}
+
+/// An `Error` on its way over the wire. The errors callers tell apart, like a
+/// bad input or a dead actor, arrive as the same variant; anything else
+/// arrives as `Error::Remote` with its message.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WireError {
+    Input { path: PathBuf, message: String },
+    Io(String),
+    ActorGone(String),
+    WriteRejected(PathBuf),
+    PartitionOutOfRange { partition: usize, partitions: usize },
+    Other(String),
+}
+
+impl From<&Error> for WireError {
+    fn from(e: &Error) -> Self {
+        match e {
+            Error::InputError(path, e) => WireError::Input {
+                path: path.clone(),
+                message: e.to_string(),
+            },
+            Error::Io(e) | Error::DirectoryReadError(e) => WireError::Io(e.to_string()),
+            Error::ActorGone(actor) => WireError::ActorGone(actor.to_string()),
+            Error::WriteRejected(path) => WireError::WriteRejected(path.clone()),
+            Error::PartitionOutOfRange {
+                partition,
+                partitions,
+            } => WireError::PartitionOutOfRange {
+                partition: *partition,
+                partitions: *partitions,
+            },
+            e => WireError::Other(e.to_string()),
+        }
+    }
+}
+
+impl From<WireError> for Error {
+    fn from(e: WireError) -> Self {
+        match e {
+            WireError::Input { path, message } => {
+                Error::InputError(path, std::io::Error::other(message))
+            }
+            WireError::Io(message) => Error::Io(std::io::Error::other(message)),
+            WireError::ActorGone(actor) => match actor.as_str() {
+                "mapper" => Error::ActorGone("mapper"),
+                "reducer" => Error::ActorGone("reducer"),
+                "writer" => Error::ActorGone("writer"),
+                _ => Error::Remote(format!("The {} actor is gone", actor)),
+            },
+            WireError::WriteRejected(path) => Error::WriteRejected(path),
+            WireError::PartitionOutOfRange {
+                partition,
+                partitions,
+            } => Error::PartitionOutOfRange {
+                partition,
+                partitions,
+            },
+            WireError::Other(message) => Error::Remote(message),
+        }
+    }
+}
+
+/// How a handle reaches its actor: through a channel to an actor in this
+/// process, or through a connection to one that `serve` answers for elsewhere.
+/// Either way a call gets the actor's reply, or `Error::ActorGone` if the actor
+/// (or the connection to it) went away first.
+pub enum Transport<Req, Resp> {
+    Local {
+        actor: &'static str,
+        sender: mpsc::Sender<Call<Req, Resp>>,
+    },
+    Remote {
+        actor: &'static str,
+        client: Arc<Client<Req, Resp>>,
+    },
+}
+
+// derive(Clone) would needlessly require Req: Clone and Resp: Clone
+impl<Req, Resp> Clone for Transport<Req, Resp> {
+    fn clone(&self) -> Self {
+        match self {
+            Transport::Local { actor, sender } => Transport::Local {
+                actor,
+                sender: sender.clone(),
+            },
+            Transport::Remote { actor, client } => Transport::Remote {
+                actor,
+                client: client.clone(),
+            },
+        }
+    }
+}
+
+type Pending<Resp> = HashMap<u64, oneshot::Sender<Result<Resp>>>;
+
+/// The calling end of a connection to a served actor. Replies are matched to
+/// their calls by id, so any number of calls can be in flight at once.
+pub struct Client<Req, Resp> {
+    writer: Mutex<Box<dyn AsyncWrite + Send + Unpin>>,
+    // calls waiting for a reply, `None` once the connection is gone
+    pending: Arc<std::sync::Mutex<Option<Pending<Resp>>>>,
+    next_id: AtomicU64,
+    // the task reading replies, which holds the other half of the connection
+    reader: tokio::task::AbortHandle,
+    requests: PhantomData<fn(Req)>,
+}
+
+// the connection only closes once both of its halves are gone, so without this
+// an actor served elsewhere would never hear that its last handle was dropped
+impl<Req, Resp> Drop for Client<Req, Resp> {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl<Req, Resp> Transport<Req, Resp>
+where
+    Req: Serialize + Send + Sync + 'static,
+    Resp: DeserializeOwned + Send + Sync + 'static,
+{
+    /// A transport to an actor that takes its calls from `sender`'s channel.
+    pub fn local(actor: &'static str, sender: mpsc::Sender<Call<Req, Resp>>) -> Self {
+        Transport::Local { actor, sender }
+    }
+
+    /// A transport to an actor served on the other end of `stream`.
+    pub fn connect<S>(actor: &'static str, stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let pending: Arc<std::sync::Mutex<Option<Pending<Resp>>>> =
+            Arc::new(std::sync::Mutex::new(Some(HashMap::new())));
+        let replies = pending.clone();
+        let reader = tokio::spawn(async move {
+            loop {
+                let reply =
+                    read_frame::<_, std::result::Result<Resp, WireError>>(&mut reader).await;
+                let Ok(Some((id, reply))) = reply else {
+                    break;
+                };
+                let waiting = replies
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .and_then(|pending| pending.remove(&id));
+                if let Some(respond_to) = waiting {
+                    let _ = respond_to.send(reply.map_err(Error::from));
+                }
+            }
+            // dropping the senders fails every call still waiting
+            replies.lock().unwrap().take();
+        })
+        .abort_handle();
+        Transport::Remote {
+            actor,
+            client: Arc::new(Client {
+                writer: Mutex::new(Box::new(writer)),
+                pending,
+                next_id: AtomicU64::new(0),
+                reader,
+                requests: PhantomData,
+            }),
+        }
+    }
+
+    /// Sends `request` to the actor and waits for its reply.
+    pub async fn call(&self, request: Req) -> Result<Resp> {
+        let (respond_to, reply) = oneshot::channel();
+        match self {
+            Transport::Local { actor, sender } => {
+                sender
+                    .send((request, respond_to))
+                    .await
+                    .map_err(|_| Error::ActorGone(actor))?;
+                reply.await.map_err(|_| Error::ActorGone(actor))?
+            }
+            Transport::Remote { actor, client } => {
+                let id = client.next_id.fetch_add(1, Ordering::Relaxed);
+                match client.pending.lock().unwrap().as_mut() {
+                    Some(pending) => pending.insert(id, respond_to),
+                    None => return Err(Error::ActorGone(actor)),
+                };
+                let written = write_frame(&mut *client.writer.lock().await, id, &request).await;
+                if written.is_err() {
+                    if let Some(pending) = client.pending.lock().unwrap().as_mut() {
+                        pending.remove(&id);
+                    }
+                    return Err(Error::ActorGone(actor));
+                }
+                reply.await.map_err(|_| Error::ActorGone(actor))?
+            }
+        }
+    }
+}
+
+/// Answers the calls that arrive on `stream` by passing them on to `transport`,
+/// until the other end hangs up or sends something that is not a call.
+pub async fn serve<Req, Resp, S>(stream: S, transport: Transport<Req, Resp>) -> Result<()>
+where
+    Req: Serialize + DeserializeOwned + Send + Sync + 'static,
+    Resp: Serialize + DeserializeOwned + Send + Sync + 'static,
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (mut reader, writer) = tokio::io::split(stream);
+    let writer = Arc::new(Mutex::new(writer));
+    while let Some((id, request)) = read_frame::<_, Req>(&mut reader).await? {
+        let transport = transport.clone();
+        let writer = writer.clone();
+        tokio::spawn(async move {
+            let reply = transport
+                .call(request)
+                .await
+                .map_err(|e| WireError::from(&e));
+            let _ = write_frame(&mut *writer.lock().await, id, &reply).await;
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_frames() {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, 7, &String::from("hello"))
+            .await
+            .unwrap();
+        let mut expected = vec![2, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(b"hello");
+        assert_eq!(&bytes[4..], expected.as_slice());
+        let mut reader = bytes.as_slice();
+        let frame: Option<(u64, String)> = read_frame(&mut reader).await.unwrap();
+        assert_eq!(frame, Some((7, String::from("hello"))));
+        assert!(read_frame::<_, String>(&mut reader)
+            .await
+            .unwrap()
+            .is_none());
+
+        let old = bincode::serialize(&Frame {
+            version: 1,
+            id: 7,
+            body: "hello",
+        })
+        .unwrap();
+        let mut bytes = (old.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&old);
+        assert!(matches!(
+            read_frame::<_, String>(&mut bytes.as_slice()).await,
+            Err(Error::Protocol(_))
+        ));
+        // record payloads go over the wire at their own size
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, 7, &(vec![0xffu8; 1000], vec![0xffu8; 1000]))
+            .await
+            .unwrap();
+        assert!(bytes.len() < 2100);
+        let huge = u32::MAX.to_be_bytes();
+        assert!(matches!(
+            read_frame::<_, String>(&mut huge.as_slice()).await,
+            Err(Error::Protocol(_))
+        ));
+    }
+
+    #[test]
+    fn test_wire_errors() {
+        let input = Error::InputError(PathBuf::from("a.txt"), std::io::Error::other("bad"));
+        assert!(matches!(
+            Error::from(WireError::from(&input)),
+            Error::InputError(ref path, ref e) if path.as_path() == Path::new("a.txt") && e.to_string() == "bad"
+        ));
+        assert!(matches!(
+            Error::from(WireError::from(&Error::ActorGone("mapper"))),
+            Error::ActorGone("mapper")
+        ));
+        assert!(matches!(
+            Error::from(WireError::from(&Error::CoreError)),
+            Error::Remote(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_local_and_remote_calls() {
+        // an actor that doubles numbers, and stops at zero
+        let (sender, mut calls) = mpsc::channel::<Call<u32, u32>>(8);
+        tokio::spawn(async move {
+            while let Some((n, respond_to)) = calls.recv().await {
+                if n == 0 {
+                    break;
+                }
+                let _ = respond_to.send(Ok(n * 2));
+            }
+        });
+        let local = Transport::local("doubler", sender);
+        assert_eq!(local.call(2).await.unwrap(), 4);
+
+        let (client, server) = tokio::io::duplex(64);
+        tokio::spawn(serve(server, local.clone()));
+        let remote = Transport::<u32, u32>::connect("doubler", client);
+        let (a, b) = tokio::join!(remote.call(3), remote.call(5));
+        assert_eq!((a.unwrap(), b.unwrap()), (6, 10));
+
+        assert!(remote.call(0).await.is_err());
+        assert!(matches!(
+            local.call(1).await,
+            Err(Error::ActorGone("doubler"))
+        ));
+    }
+}
@@ -1,27 +1,55 @@
-use std::{collections::HashMap, path::PathBuf};
+use crate::record::{Blocks, Compression, Record, RecordFormat};
+use crate::transport::{Call, Transport};
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    path::{Path, PathBuf},
+};
 use tokio::{
     fs::File,
     io::{AsyncWriteExt, BufWriter},
     sync::{
         mpsc::{self, channel},
-        oneshot, Mutex,
+        Mutex,
     },
 };
 
-#[derive(Debug)]
+/// Size of each file's write buffer. Batched records only reach the file once
+/// it fills up or writing ends.
+pub const WRITE_BUFFER_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Request {
     pub header: RequestHeader,
     pub body: Option<String>,
 }
 
-#[derive(Debug)]
+/// Records for one file, written in a single message in the writer's record format.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    pub key: PathBuf,
+    pub records: Vec<Record>,
+}
+
+/// What the writer wrote to a file over the whole job.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStats {
+    /// Bytes written to the file.
+    pub bytes: u64,
+    /// Bytes of records before compression.
+    pub raw_bytes: u64,
+    pub records: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Response {
     header: ResponseHeader,
     body: Option<String>,
     pub status: usize,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum RequestHeader {
     Prepare,
     Payload { key: PathBuf },
@@ -29,170 +57,373 @@ pub enum RequestHeader {
     Error,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 enum ResponseHeader {
     Ready,
     Finished,
     Error,
 }
 
-#[derive(Debug)]
-enum WriterMessage {
-    BeginWriting {
-        filename: PathBuf,
-        respond_to: oneshot::Sender<Response>,
-    },
-    Write {
-        message: Request,
-        respond_to: oneshot::Sender<Response>,
-    },
-    EndWriting {
-        respond_to: oneshot::Sender<Response>,
-    },
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriterRequest {
+    BeginWriting { filename: PathBuf },
+    Write { message: Request },
+    WriteBatch { batches: Vec<Batch> },
+    EndWriting { filename: PathBuf },
+    AbortWriting { filename: PathBuf },
+    Shutdown,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WriterResponse {
+    Response(Response),
+    /// What was written to a file that was just committed.
+    Committed(FileStats),
+    Aborted,
+    /// What was written to every committed file, once the writer stopped.
+    Stopped(HashMap<PathBuf, FileStats>),
 }
 
 struct Writer {
     message_id: Mutex<usize>,
     bufwriter: HashMap<PathBuf, Mutex<BufWriter<File>>>,
-    receiver: mpsc::Receiver<WriterMessage>,
+    // records of each open file not yet framed into a block
+    blocks: HashMap<PathBuf, Blocks>,
+    format: RecordFormat,
+    compression: Compression,
+    // what was written to each open file, and to each committed one
+    pending: HashMap<PathBuf, FileStats>,
+    stats: HashMap<PathBuf, FileStats>,
+    receiver: mpsc::Receiver<Call<WriterRequest, WriterResponse>>,
+    running: bool,
 }
 impl Writer {
-    fn new(receiver: mpsc::Receiver<WriterMessage>) -> Self {
+    fn new(
+        receiver: mpsc::Receiver<Call<WriterRequest, WriterResponse>>,
+        format: RecordFormat,
+        compression: Compression,
+    ) -> Self {
         Self {
             message_id: Mutex::new(0),
             bufwriter: HashMap::new(),
+            blocks: HashMap::new(),
+            format,
+            compression,
+            pending: HashMap::new(),
+            stats: HashMap::new(),
             receiver,
+            running: true,
         }
     }
-    async fn handle_message(&mut self, message: WriterMessage) {
-        match message {
-            WriterMessage::BeginWriting {
-                filename,
-                respond_to,
-            } => {
+    async fn handle_message(&mut self, request: WriterRequest) -> Result<WriterResponse> {
+        match request {
+            WriterRequest::BeginWriting { filename } => {
                 let mut guard = self.message_id.lock().await;
                 *guard += 1;
                 drop(guard);
 
-                self.bufwriter.entry(filename.clone()).or_insert({
+                // the data goes to a temporary file until EndWriting renames it into
+                // place, so a reader never sees a file that is only half written
+                if let Entry::Vacant(entry) = self.bufwriter.entry(filename) {
+                    if let Some(dir) = entry.key().parent() {
+                        tokio::fs::create_dir_all(dir).await?;
+                    }
                     let file = File::options()
-                        .append(true)
+                        .write(true)
                         .create(true)
-                        .open(&filename)
-                        .await
-                        .unwrap();
-                    Mutex::new(BufWriter::new(file))
-                });
+                        .truncate(true)
+                        .open(temp_path(entry.key()))
+                        .await?;
+                    self.pending
+                        .insert(entry.key().clone(), FileStats::default());
+                    let blocks = Blocks::new(self.format).with_compression(self.compression);
+                    self.blocks.insert(entry.key().clone(), blocks);
+                    entry.insert(Mutex::new(BufWriter::with_capacity(
+                        WRITE_BUFFER_BYTES,
+                        file,
+                    )));
+                }
 
-                let response = Response {
+                Ok(WriterResponse::Response(Response {
                     header: ResponseHeader::Ready,
                     body: Some(String::from("BeginWriting Finished")),
                     status: 200,
-                };
-
-                let _ = respond_to.send(response);
+                }))
             }
 
-            WriterMessage::Write {
-                message,
-                respond_to,
-            } => {
+            WriterRequest::Write { message } => {
                 let mut guard = self.message_id.lock().await;
                 *guard += 1;
                 drop(guard);
 
-                match message.header {
-                    RequestHeader::Prepare => {
-                        let _ = respond_to.send(Response {
-                            header: ResponseHeader::Ready,
-                            body: None,
-                            status: 200,
-                        });
-                    }
+                let response = match message.header {
+                    RequestHeader::Prepare => Response {
+                        header: ResponseHeader::Ready,
+                        body: None,
+                        status: 200,
+                    },
                     RequestHeader::Payload { key } => {
                         if let Some(writer) = self.bufwriter.get(&key) {
                             let mut guard = writer.lock().await;
-                            guard
-                                .write(message.body.unwrap().as_bytes())
-                                .await
-                                .expect("issue writing to file");
-
-                            guard.flush().await.expect("issue flushing file");
+                            let body = message.body.unwrap_or_default();
+                            guard.write_all(body.as_bytes()).await?;
+                            guard.flush().await?;
                             drop(guard);
-                            let _ = respond_to.send(Response {
+                            let stats = self.pending.entry(key).or_default();
+                            stats.bytes += body.len() as u64;
+                            stats.raw_bytes += body.len() as u64;
+                            stats.records += 1;
+                            Response {
                                 header: ResponseHeader::Finished,
                                 body: None,
                                 status: 200,
-                            });
+                            }
                         } else {
-                            let _ = respond_to.send(Response {
+                            Response {
                                 header: ResponseHeader::Error,
                                 body: None,
                                 status: 400,
-                            });
+                            }
                         }
                     }
-                    RequestHeader::Cleanup => {
-                        let _ = respond_to.send(Response {
-                            header: ResponseHeader::Finished,
-                            body: None,
-                            status: 200,
-                        });
-                    }
-                    RequestHeader::Error => {
-                        let _ = respond_to.send(Response {
-                            header: ResponseHeader::Error,
-                            body: None,
-                            status: 400,
-                        });
-                    }
+                    RequestHeader::Cleanup => Response {
+                        header: ResponseHeader::Finished,
+                        body: None,
+                        status: 200,
+                    },
+                    RequestHeader::Error => Response {
+                        header: ResponseHeader::Error,
+                        body: None,
+                        status: 400,
+                    },
+                };
+                Ok(WriterResponse::Response(response))
+            }
+            WriterRequest::WriteBatch { batches } => {
+                let mut guard = self.message_id.lock().await;
+                *guard += 1;
+                drop(guard);
+
+                // reject the whole batch up front rather than leave half of it written
+                if batches
+                    .iter()
+                    .any(|batch| !self.bufwriter.contains_key(&batch.key))
+                {
+                    return Ok(WriterResponse::Response(Response {
+                        header: ResponseHeader::Error,
+                        body: None,
+                        status: 400,
+                    }));
+                }
+                self.write_batches(batches).await?;
+                Ok(WriterResponse::Response(Response {
+                    header: ResponseHeader::Finished,
+                    body: None,
+                    status: 200,
+                }))
+            }
+
+            WriterRequest::EndWriting { filename } => self
+                .end_writing(filename)
+                .await
+                .map(WriterResponse::Committed),
+
+            WriterRequest::AbortWriting { filename } => {
+                self.abort_writing(filename).await?;
+                Ok(WriterResponse::Aborted)
+            }
+
+            WriterRequest::Shutdown => {
+                // stop taking messages even if discarding a file fails, nobody can
+                // trust what this writer has written anymore
+                self.running = false;
+                // whatever was never ended belongs to an attempt that did not finish
+                let open: Vec<PathBuf> = self.bufwriter.keys().cloned().collect();
+                for filename in open {
+                    self.abort_writing(filename).await?;
+                }
+                Ok(WriterResponse::Stopped(std::mem::take(&mut self.stats)))
+            }
+        }
+    }
+
+    // flushes, fsyncs and closes one file, then renames it into place
+    async fn end_writing(&mut self, filename: PathBuf) -> Result<FileStats> {
+        let Some(writer) = self.bufwriter.remove(&filename) else {
+            return Err(Error::WriteRejected(filename));
+        };
+        let mut stats = self.pending.remove(&filename).unwrap_or_default();
+        let last_block = match self.blocks.remove(&filename) {
+            Some(mut blocks) => {
+                stats.raw_bytes += blocks.pending_bytes() as u64;
+                blocks.take()
+            }
+            None => Vec::new(),
+        };
+        stats.bytes += last_block.len() as u64;
+        let mut writer = writer.into_inner();
+        let committed = async {
+            writer.write_all(&last_block).await?;
+            writer.flush().await?;
+            writer.get_ref().sync_all().await?;
+            drop(writer);
+            tokio::fs::rename(temp_path(&filename), &filename).await
+        }
+        .await;
+        if let Err(e) = committed {
+            let _ = tokio::fs::remove_file(temp_path(&filename)).await;
+            return Err(Error::Io(e));
+        }
+        self.stats.insert(filename, stats);
+        Ok(stats)
+    }
+
+    async fn abort_writing(&mut self, filename: PathBuf) -> Result<()> {
+        let Some(writer) = self.bufwriter.remove(&filename) else {
+            return Err(Error::WriteRejected(filename));
+        };
+        drop(writer);
+        self.pending.remove(&filename);
+        self.blocks.remove(&filename);
+        tokio::fs::remove_file(temp_path(&filename)).await?;
+        Ok(())
+    }
+
+    // the BufWriters only hit the disk when their buffer fills up, not once per record
+    async fn write_batches(&mut self, batches: Vec<Batch>) -> std::io::Result<()> {
+        for batch in batches {
+            let mut guard = self.bufwriter[&batch.key].lock().await;
+            let stats = self.pending.entry(batch.key.clone()).or_default();
+            let blocks = self
+                .blocks
+                .entry(batch.key.clone())
+                .or_insert_with(|| Blocks::new(self.format).with_compression(self.compression));
+            for (key, value) in batch.records.iter() {
+                blocks.push(key, value);
+                stats.records += 1;
+                if blocks.is_full() {
+                    stats.raw_bytes += blocks.pending_bytes() as u64;
+                    let block = blocks.take();
+                    guard.write_all(&block).await?;
+                    stats.bytes += block.len() as u64;
                 }
             }
-            _ => {}
         }
+        Ok(())
     }
 }
 
+/// Where a file is written until it is ended: its name with `.tmp` appended.
+pub fn temp_path(filename: &Path) -> PathBuf {
+    let mut name = filename.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
 #[derive(Clone)]
 pub struct WriterHandle {
-    sender: mpsc::Sender<WriterMessage>,
+    transport: Transport<WriterRequest, WriterResponse>,
 }
 async fn run_writer(mut writer: Writer) {
-    while let Some(message) = writer.receiver.recv().await {
-        writer.handle_message(message).await;
+    while let Some((request, respond_to)) = writer.receiver.recv().await {
+        let _ = respond_to.send(writer.handle_message(request).await);
+        if !writer.running {
+            break;
+        }
     }
 }
 
+fn unexpected(response: WriterResponse) -> Error {
+    Error::Protocol(format!("unexpected writer response {:?}", response))
+}
+
 impl WriterHandle {
     pub async fn new() -> Self {
+        Self::with_format(RecordFormat::Binary, Compression::None).await
+    }
+
+    /// Spawns a writer that frames batched records in `format`, compressing
+    /// binary blocks with `compression`. The format must match the one the
+    /// mappers encode their values in.
+    pub async fn with_format(format: RecordFormat, compression: Compression) -> Self {
         let (sender, receiver) = channel(100);
-        let writer = Writer::new(receiver);
+        let writer = Writer::new(receiver, format, compression);
         tokio::spawn(run_writer(writer));
 
-        return Self { sender };
+        Self::with_transport(Transport::local("writer", sender))
     }
 
-    pub async fn begin_writing(&mut self, filename: PathBuf) -> Response {
-        let (send, recv) = oneshot::channel();
-        let message = WriterMessage::BeginWriting {
-            respond_to: send,
-            filename,
-        };
-        let _ = self.sender.send(message).await;
-        recv.await.expect("Actor ded")
+    /// A handle to a writer reached through `transport`, e.g. one served on a socket.
+    pub fn with_transport(transport: Transport<WriterRequest, WriterResponse>) -> Self {
+        Self { transport }
     }
 
-    pub async fn write_message(&mut self, message: Request) -> Response {
-        let (send, recv) = oneshot::channel();
-        println!("Sending message: {:?}", message.body);
-        let message = WriterMessage::Write {
-            respond_to: send,
-            message,
-        };
+    /// Where the writer behind this handle takes its calls, to serve it elsewhere.
+    pub fn transport(&self) -> Transport<WriterRequest, WriterResponse> {
+        self.transport.clone()
+    }
+
+    async fn call_for_response(&self, request: WriterRequest) -> Result<Response> {
+        match self.transport.call(request).await? {
+            WriterResponse::Response(response) => Ok(response),
+            other => Err(unexpected(other)),
+        }
+    }
 
-        let _ = self.sender.send(message).await;
+    pub async fn begin_writing(&mut self, filename: PathBuf) -> Result<Response> {
+        self.call_for_response(WriterRequest::BeginWriting { filename })
+            .await
+    }
+
+    pub async fn write_message(&mut self, message: Request) -> Result<Response> {
+        self.call_for_response(WriterRequest::Write { message })
+            .await
+    }
+
+    /// Writes many records to files that have already begun writing. The
+    /// whole batch is rejected with a 400 if any of its files has not.
+    pub async fn write_batch(&mut self, batches: Vec<Batch>) -> Result<Response> {
+        self.call_for_response(WriterRequest::WriteBatch { batches })
+            .await
+    }
+
+    /// Commits a file: flushes, fsyncs and closes it, then atomically renames
+    /// it into place, replacing any earlier version. Returns what was written
+    /// to it. Writing to it again needs a new `begin_writing`. Only this one
+    /// file is committed atomically: files committed in turn can be left with
+    /// some committed and some not.
+    pub async fn end_writing(&mut self, filename: PathBuf) -> Result<FileStats> {
+        match self
+            .transport
+            .call(WriterRequest::EndWriting { filename })
+            .await?
+        {
+            WriterResponse::Committed(stats) => Ok(stats),
+            other => Err(unexpected(other)),
+        }
+    }
+
+    /// Discards everything written to a file since `begin_writing`. An earlier
+    /// committed version of the file is left alone.
+    pub async fn abort_writing(&mut self, filename: PathBuf) -> Result<()> {
+        match self
+            .transport
+            .call(WriterRequest::AbortWriting { filename })
+            .await?
+        {
+            WriterResponse::Aborted => Ok(()),
+            other => Err(unexpected(other)),
+        }
+    }
 
-        return recv.await.expect("Actor ded");
+    /// Aborts every file that was never ended and stops the writer, returning
+    /// what was written to each committed file. Every handle to the writer is
+    /// useless afterwards.
+    pub async fn shutdown(&mut self) -> Result<HashMap<PathBuf, FileStats>> {
+        match self.transport.call(WriterRequest::Shutdown).await? {
+            WriterResponse::Stopped(stats) => Ok(stats),
+            other => Err(unexpected(other)),
+        }
     }
 }
 
@@ -203,14 +434,29 @@ mod tests {
     #[tokio::test]
     async fn test_begin_writing() {
         let mut writer = WriterHandle::new().await;
-        let packet = writer.begin_writing(PathBuf::from("test.txt")).await;
+        let packet = writer
+            .begin_writing(PathBuf::from("test.txt"))
+            .await
+            .unwrap();
         assert_eq!(packet.status, 200);
+
+        // aborting leaves the existing file alone
+        let before = std::fs::read_to_string("test.txt").unwrap();
+        writer
+            .abort_writing(PathBuf::from("test.txt"))
+            .await
+            .unwrap();
+        assert!(!Path::new("test.txt.tmp").exists());
+        assert_eq!(std::fs::read_to_string("test.txt").unwrap(), before);
     }
 
     #[tokio::test]
     async fn test_write_message() {
         let mut writer = WriterHandle::new().await;
-        let zero = writer.begin_writing(PathBuf::from("test2.txt")).await;
+        let zero = writer
+            .begin_writing(PathBuf::from("test2.txt"))
+            .await
+            .unwrap();
         println!("{:?}", zero);
         assert_eq!(zero.status, 200);
 
@@ -218,7 +464,7 @@ mod tests {
             header: RequestHeader::Prepare,
             body: Some(String::from("We're starting now")),
         };
-        let first = writer.write_message(first_message).await;
+        let first = writer.write_message(first_message).await.unwrap();
         println!("{:?}", first);
         assert_eq!(first.status, 200);
 
@@ -228,7 +474,7 @@ mod tests {
             },
             body: Some(String::from("A new dog is here!")),
         };
-        let second = writer.write_message(message_second).await;
+        let second = writer.write_message(message_second).await.unwrap();
         println!("{:?}", second);
         assert_eq!(second.status, 200);
 
@@ -237,9 +483,15 @@ mod tests {
             body: Some(String::from("No more data!")),
         };
 
-        let third = writer.write_message(message_third).await;
+        let third = writer.write_message(message_third).await.unwrap();
         println!("{:?}", third);
         assert_eq!(third.status, 200);
+        // not visible until it is committed
+        assert!(!Path::new("test2.txt").exists());
+        writer
+            .end_writing(PathBuf::from("test2.txt"))
+            .await
+            .unwrap();
 
         let file_contents = std::fs::read_to_string("test2.txt").unwrap();
         assert_eq!(file_contents, "A new dog is here!");
@@ -247,4 +499,109 @@ mod tests {
         //clean up file
         std::fs::remove_file("test2.txt").unwrap();
     }
+
+    #[tokio::test]
+    async fn test_write_batch() {
+        let path = std::env::temp_dir().join(format!("batch-{}.txt", std::process::id()));
+        let mut writer = WriterHandle::with_format(RecordFormat::Text, Compression::None).await;
+        writer.begin_writing(path.clone()).await.unwrap();
+
+        let batch = vec![Batch {
+            key: path.clone(),
+            records: vec![
+                (b"dog".to_vec(), b"1".to_vec()),
+                (b"cat".to_vec(), b"2".to_vec()),
+            ],
+        }];
+        assert_eq!(writer.write_batch(batch).await.unwrap().status, 200);
+        assert!(!path.exists());
+        let stats = writer.end_writing(path.clone()).await.unwrap();
+        assert_eq!(
+            stats,
+            FileStats {
+                bytes: 12,
+                raw_bytes: 12,
+                records: 2
+            }
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "dog:1\ncat:2\n");
+
+        let unknown = vec![Batch {
+            key: PathBuf::from("never-begun.txt"),
+            records: vec![(b"dog".to_vec(), b"1".to_vec())],
+        }];
+        assert_eq!(writer.write_batch(unknown).await.unwrap().status, 400);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_writer_over_stream() {
+        let path = std::env::temp_dir().join(format!("remote-{}.txt", std::process::id()));
+        let local = WriterHandle::with_format(RecordFormat::Text, Compression::None).await;
+        let (client, server) = tokio::io::duplex(1024);
+        tokio::spawn(crate::transport::serve(server, local.transport()));
+        let mut writer = WriterHandle::with_transport(Transport::connect("writer", client));
+
+        writer.begin_writing(path.clone()).await.unwrap();
+        let batch = vec![Batch {
+            key: path.clone(),
+            records: vec![(b"dog".to_vec(), b"1".to_vec())],
+        }];
+        assert_eq!(writer.write_batch(batch).await.unwrap().status, 200);
+        assert_eq!(writer.end_writing(path.clone()).await.unwrap().records, 1);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "dog:1\n");
+        assert!(matches!(
+            writer.end_writing(path.clone()).await,
+            Err(Error::WriteRejected(ref rejected)) if *rejected == path
+        ));
+        assert_eq!(writer.shutdown().await.unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let path = std::env::temp_dir().join(format!("shutdown-{}.txt", std::process::id()));
+        let unfinished = path.with_extension("unfinished");
+        let mut writer = WriterHandle::with_format(RecordFormat::Text, Compression::None).await;
+        for key in [&path, &unfinished] {
+            writer.begin_writing(key.clone()).await.unwrap();
+            let batch = vec![Batch {
+                key: key.clone(),
+                records: vec![(b"dog".to_vec(), b"1".to_vec())],
+            }];
+            writer.write_batch(batch).await.unwrap();
+        }
+        writer.end_writing(path.clone()).await.unwrap();
+
+        // the file that was never ended is thrown away
+        let stats = writer.shutdown().await.unwrap();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(
+            stats[&path],
+            FileStats {
+                bytes: 6,
+                raw_bytes: 6,
+                records: 1
+            }
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "dog:1\n");
+        assert!(!temp_path(&unfinished).exists());
+        assert!(matches!(
+            writer.begin_writing(path.clone()).await,
+            Err(Error::ActorGone("writer"))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_begin_writing_unwritable_file() {
+        let mut writer = WriterHandle::new().await;
+        let result = writer
+            .begin_writing(PathBuf::from("./test.txt/0.txt"))
+            .await;
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
 }
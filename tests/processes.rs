@@ -0,0 +1,149 @@
+//! Runs the built binary with `--processes`, the way it is run by hand, and
+//! checks its results against a job whose mappers and reducers are actors.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+
+const BIN: &str = env!("CARGO_BIN_EXE_tinymapreduce");
+
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Writes enough input that mapping it keeps the children busy for a while.
+fn write_input(dir: &Path) -> PathBuf {
+    let input = dir.join("input");
+    std::fs::create_dir_all(&input).unwrap();
+    for file in 0..16 {
+        let mut text = String::new();
+        for line in 0..20_000 {
+            text.push_str(&format!(
+                "word{} common line{} file{}\n",
+                line % 500,
+                line % 7,
+                file
+            ));
+        }
+        std::fs::write(input.join(format!("{}.txt", file)), text).unwrap();
+    }
+    input
+}
+
+/// A job over `input` writing its results to `dir/output`.
+fn job(input: &Path, dir: &Path, output: &str, extra_args: &[&str]) -> Command {
+    let mut command = Command::new(BIN);
+    command
+        .arg(input)
+        .arg("-o")
+        .arg(dir.join(output))
+        .arg("-s")
+        .arg(dir.join("scratch"))
+        .args(["-m", "2", "-r", "3", "--split-size", "65536"])
+        .args(extra_args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    command
+}
+
+fn assert_success(output: &Output) {
+    assert!(
+        output.status.success(),
+        "the job failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn result(dir: &Path, output: &str) -> String {
+    std::fs::read_to_string(dir.join(output).join("result.tsv")).unwrap()
+}
+
+#[test]
+fn test_processes_match_actors() {
+    let dir = test_dir("processes-match-test");
+    let input = write_input(&dir);
+    assert_success(&job(&input, &dir, "actors", &[]).output().unwrap());
+    assert_success(
+        &job(&input, &dir, "processes", &["--processes"])
+            .output()
+            .unwrap(),
+    );
+    assert_eq!(result(&dir, "processes"), result(&dir, "actors"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_processes_survive_killed_child() {
+    let dir = test_dir("processes-kill-test");
+    let input = write_input(&dir);
+    assert_success(&job(&input, &dir, "actors", &[]).output().unwrap());
+
+    let running = job(&input, &dir, "processes", &["--processes"])
+        .spawn()
+        .unwrap();
+    // one child as it starts up, and another once the children are mapping
+    let killed_starting = kill_child(running.id());
+    std::thread::sleep(Duration::from_millis(300));
+    let killed_mapping = kill_child(running.id());
+    let output = running.wait_with_output().unwrap();
+    assert!(
+        killed_starting && killed_mapping,
+        "the job was over before two of its children were killed"
+    );
+    assert_success(&output);
+    assert_eq!(result(&dir, "processes"), result(&dir, "actors"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Kills the first child process of `parent` that comes up, a mapper or a
+/// reducer. Returns whether there was one to kill before the job ended.
+#[cfg(target_os = "linux")]
+fn kill_child(parent: u32) -> bool {
+    for _ in 0..2000 {
+        for child in children(parent) {
+            let status = Command::new("kill")
+                .arg("-9")
+                .arg(child.to_string())
+                .status();
+            if status.is_ok_and(|status| status.success()) {
+                return true;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+    false
+}
+
+/// The processes `parent` started as `tinymapreduce child <SOCKET>`, from `/proc`.
+#[cfg(target_os = "linux")]
+fn children(parent: u32) -> Vec<u32> {
+    let mut children = Vec::new();
+    for entry in std::fs::read_dir("/proc").unwrap().flatten() {
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else {
+            continue;
+        };
+        // the state and the parent's pid are the fields after the name,
+        // which is in parentheses and may hold spaces
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        let Some((_, fields)) = stat.rsplit_once(')') else {
+            continue;
+        };
+        let mut fields = fields.split_whitespace();
+        // a child killed already lingers until it is reaped
+        if fields.next() == Some("Z") || fields.next() != Some(parent.to_string().as_str()) {
+            continue;
+        }
+        let cmdline = std::fs::read(entry.path().join("cmdline")).unwrap_or_default();
+        if cmdline.split(|byte| *byte == 0).any(|arg| arg == b"child") {
+            children.push(pid);
+        }
+    }
+    children
+}